@@ -0,0 +1,36 @@
+//! A minimal AT: watch the keyboard, grab `Caps_Lock` as a global modifier, and print every key
+//! event the compositor sends.
+//!
+//! Run it with `cargo run --example watch` inside a session whose compositor implements
+//! `org.freedesktop.a11y.KeyboardMonitor` and allows this client to use it.
+
+use freedesktop_a11y_keyboardmonitor::client::key_events;
+use freedesktop_a11y_keyboardmonitor::{KeyboardMonitorProxy, Keysym};
+use futures_lite::StreamExt;
+use xkeysym::Keysym as XKeysym;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    zbus::block_on(async {
+        let proxy = KeyboardMonitorProxy::connect().await?;
+        // subscribe before changing the grabs, so no event is missed
+        let mut events = key_events(&proxy).await?;
+        proxy.try_watch_keyboard().await?;
+        proxy
+            .try_set_key_grabs(&[Keysym(XKeysym::Caps_Lock)], &[])
+            .await?;
+        println!("Watching the keyboard; hold Caps Lock to grab it, Ctrl+C to quit.");
+        while let Some(event) = events.next().await {
+            let action = if event.release() {
+                "released"
+            } else {
+                "pressed"
+            };
+            print!("{action} {} (keycode {}", event.keysym(), event.keycode());
+            if let Some(c) = event.unichar() {
+                print!(", {c:?}");
+            }
+            println!(", state {:#x})", event.state().0.raw());
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,164 @@
+//! AT side helpers on top of [`KeyboardMonitorProxy`].
+
+use crate::error::KeyboardMonitorError;
+use crate::state_machine::KeyEvent;
+use crate::{KeyboardMonitorProxy, Keysym, ModMask};
+use futures_lite::{Stream, StreamExt};
+use zbus::{Connection, connection};
+
+/// Subscribe to the `KeyEvent` signal, decoding each emission into a [`KeyEvent`].
+///
+/// A `0` unichar (or one that is not a Unicode scalar value) is reported as `None`; signals whose
+/// arguments fail to decode are skipped.
+///
+/// Dropping the stream cancels the subscription. Signals are queued by the underlying
+/// [`zbus::Connection`] until the stream is polled; once that queue is full
+/// ([`zbus::Connection::max_queued`]), the connection stops reading further messages, so keep
+/// polling the stream while it is alive.
+pub async fn key_events(
+    proxy: &KeyboardMonitorProxy<'_>,
+) -> zbus::Result<impl Stream<Item = KeyEvent> + use<>> {
+    Ok(proxy.receive_key_event().await?.filter_map(|signal| {
+        let args = signal.args().ok()?;
+        let unichar = KeyEvent::decode_unichar(args.unichar).ok().flatten();
+        Some(KeyEvent::new(
+            args.released,
+            args.state,
+            args.keysym,
+            unichar,
+            args.keycode,
+        ))
+    }))
+}
+
+/// Variants of the interface methods which classify failures as [`KeyboardMonitorError`]s, so an
+/// AT can tell a refused call apart from a broken connection.
+impl KeyboardMonitorProxy<'_> {
+    /// Connect to the session bus, and create a proxy for the compositor at the default
+    /// `org.freedesktop.a11y.Manager` name and `/org/freedesktop/a11y/Manager` path.
+    ///
+    /// # Errors
+    ///
+    /// If the session bus can not be reached.
+    pub async fn connect() -> zbus::Result<KeyboardMonitorProxy<'static>> {
+        KeyboardMonitorProxy::new(&Connection::session().await?).await
+    }
+    /// As [`KeyboardMonitorProxy::connect`], but on the message bus at the given D-Bus `address`
+    /// (e.g. `unix:path=/run/user/1000/bus`) instead of the session bus.
+    ///
+    /// # Errors
+    ///
+    /// If the address is invalid, or the bus can not be reached.
+    pub async fn connect_address(address: &str) -> zbus::Result<KeyboardMonitorProxy<'static>> {
+        let conn = connection::Builder::address(address)?.build().await?;
+        KeyboardMonitorProxy::new(&conn).await
+    }
+    /// Create a proxy for the interface at the given bus name and object `path` on `conn`,
+    /// instead of the default `org.freedesktop.a11y.Manager` and `/org/freedesktop/a11y/Manager`;
+    /// e.g. for a mock service, or a compositor serving it elsewhere.
+    ///
+    /// # Errors
+    ///
+    /// If `service` is not a valid bus name, or `path` not a valid object path.
+    pub async fn for_service(
+        conn: &Connection,
+        service: &str,
+        path: &str,
+    ) -> zbus::Result<KeyboardMonitorProxy<'static>> {
+        KeyboardMonitorProxy::builder(conn)
+            .destination(service.to_owned())?
+            .path(path.to_owned())?
+            .build()
+            .await
+    }
+    /// [`KeyboardMonitorProxy::grab_keyboard`], with a classified error.
+    pub async fn try_grab_keyboard(&self) -> Result<(), KeyboardMonitorError> {
+        Ok(self.grab_keyboard().await?)
+    }
+    /// [`KeyboardMonitorProxy::ungrab_keyboard`], with a classified error.
+    pub async fn try_ungrab_keyboard(&self) -> Result<(), KeyboardMonitorError> {
+        Ok(self.ungrab_keyboard().await?)
+    }
+    /// [`KeyboardMonitorProxy::watch_keyboard`], with a classified error.
+    pub async fn try_watch_keyboard(&self) -> Result<(), KeyboardMonitorError> {
+        Ok(self.watch_keyboard().await?)
+    }
+    /// [`KeyboardMonitorProxy::unwatch_keyboard`], with a classified error.
+    pub async fn try_unwatch_keyboard(&self) -> Result<(), KeyboardMonitorError> {
+        Ok(self.unwatch_keyboard().await?)
+    }
+    /// [`KeyboardMonitorProxy::set_key_grabs`], taking the keystrokes as owned tuples rather than
+    /// references to them.
+    pub async fn set_key_grabs_owned(
+        &self,
+        modifiers: &[Keysym],
+        keystrokes: &[(Keysym, ModMask)],
+    ) -> zbus::Result<()> {
+        let keystrokes: Vec<&(Keysym, ModMask)> = keystrokes.iter().collect();
+        self.set_key_grabs(modifiers, &keystrokes).await
+    }
+    /// [`KeyboardMonitorProxy::set_key_grabs`], with a classified error.
+    pub async fn try_set_key_grabs(
+        &self,
+        modifiers: &[Keysym],
+        keystrokes: &[&(Keysym, ModMask)],
+    ) -> Result<(), KeyboardMonitorError> {
+        Ok(self.set_key_grabs(modifiers, keystrokes).await?)
+    }
+}
+
+/// The keyboard grabbed through `GrabKeyboard`, until this guard is dropped or
+/// [released](KeyboardGrab::release); see [`KeyboardMonitorProxy::grab_guard`].
+///
+/// Dropping the guard can not wait for the `UngrabKeyboard` call: it is spawned on the
+/// connection's executor and left to complete in the background. It is therefore not sent if the
+/// process exits right away (e.g. through [`std::process::exit`]), or if the connection was built
+/// without its internal executor and is not ticked any more. Prefer [`KeyboardGrab::release`]
+/// where the grab can be ended from async code. In any case the compositor ends the grab once the
+/// AT disconnects from the bus. With the `tokio` feature, the guard must be dropped within the
+/// tokio runtime, as the ungrab is spawned onto it.
+#[derive(Debug)]
+#[must_use = "dropping the guard ungrabs the keyboard right away"]
+pub struct KeyboardGrab {
+    proxy: Option<KeyboardMonitorProxy<'static>>,
+}
+
+impl KeyboardGrab {
+    /// End the grab, waiting for the compositor to handle `UngrabKeyboard`.
+    pub async fn release(mut self) -> Result<(), KeyboardMonitorError> {
+        match self.proxy.take() {
+            Some(proxy) => proxy.try_ungrab_keyboard().await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for KeyboardGrab {
+    fn drop(&mut self) {
+        let Some(proxy) = self.proxy.take() else {
+            return;
+        };
+        let executor = proxy.inner().connection().executor().clone();
+        executor
+            .spawn(
+                async move {
+                    // nothing to report the failure to; the grab ends with the connection anyway
+                    let _ = proxy.ungrab_keyboard().await;
+                },
+                "ungrab keyboard",
+            )
+            .detach();
+    }
+}
+
+impl KeyboardMonitorProxy<'static> {
+    /// Grab the keyboard, returning a guard which ungrabs it again when dropped.
+    ///
+    /// See [`KeyboardGrab`] for the caveats of ungrabbing on drop.
+    pub async fn grab_guard(&self) -> Result<KeyboardGrab, KeyboardMonitorError> {
+        self.try_grab_keyboard().await?;
+        Ok(KeyboardGrab {
+            proxy: Some(self.clone()),
+        })
+    }
+}
@@ -0,0 +1,78 @@
+//! Errors returned by the checked [`KeyboardMonitorProxy`](crate::KeyboardMonitorProxy) methods.
+
+use std::fmt;
+use zbus::fdo;
+
+const ACCESS_DENIED: &str = "org.freedesktop.DBus.Error.AccessDenied";
+const UNSUPPORTED: [&str; 5] = [
+    "org.freedesktop.DBus.Error.ServiceUnknown",
+    "org.freedesktop.DBus.Error.NameHasNoOwner",
+    "org.freedesktop.DBus.Error.UnknownObject",
+    "org.freedesktop.DBus.Error.UnknownInterface",
+    "org.freedesktop.DBus.Error.UnknownMethod",
+];
+
+/// A failed call to the compositor, classified by the D-Bus error it returned.
+#[derive(Debug)]
+pub enum KeyboardMonitorError {
+    /// The compositor refused the call; usually the AT is not on its list of allowed clients
+    /// (see the crate level documentation).
+    AccessDenied(Option<String>),
+    /// Nothing on the bus implements `org.freedesktop.a11y.KeyboardMonitor` at the expected
+    /// name and path.
+    Unsupported(Option<String>),
+    /// Any other D-Bus or transport failure.
+    Transport(zbus::Error),
+}
+
+impl From<zbus::Error> for KeyboardMonitorError {
+    fn from(err: zbus::Error) -> Self {
+        match err {
+            zbus::Error::MethodError(name, detail, _) if name.as_str() == ACCESS_DENIED => {
+                KeyboardMonitorError::AccessDenied(detail)
+            }
+            zbus::Error::MethodError(name, detail, _) if UNSUPPORTED.contains(&name.as_str()) => {
+                KeyboardMonitorError::Unsupported(detail)
+            }
+            zbus::Error::FDO(fdo_err) => match *fdo_err {
+                fdo::Error::AccessDenied(detail) => {
+                    KeyboardMonitorError::AccessDenied(Some(detail))
+                }
+                fdo::Error::ServiceUnknown(detail)
+                | fdo::Error::NameHasNoOwner(detail)
+                | fdo::Error::UnknownObject(detail)
+                | fdo::Error::UnknownInterface(detail)
+                | fdo::Error::UnknownMethod(detail) => {
+                    KeyboardMonitorError::Unsupported(Some(detail))
+                }
+                other => KeyboardMonitorError::Transport(zbus::Error::FDO(Box::new(other))),
+            },
+            other => KeyboardMonitorError::Transport(other),
+        }
+    }
+}
+
+impl fmt::Display for KeyboardMonitorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyboardMonitorError::AccessDenied(detail) => {
+                f.write_str("access to the keyboard monitor was denied")?;
+                detail.iter().try_for_each(|d| write!(f, ": {d}"))
+            }
+            KeyboardMonitorError::Unsupported(detail) => {
+                f.write_str("no keyboard monitor is available")?;
+                detail.iter().try_for_each(|d| write!(f, ": {d}"))
+            }
+            KeyboardMonitorError::Transport(err) => write!(f, "D-Bus error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for KeyboardMonitorError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KeyboardMonitorError::Transport(err) => Some(err),
+            KeyboardMonitorError::AccessDenied(_) | KeyboardMonitorError::Unsupported(_) => None,
+        }
+    }
+}
@@ -0,0 +1,227 @@
+//! Conversion between Linux evdev key codes (`KEY_*` in `linux/input-event-codes.h`) and
+//! keysyms.
+//!
+//! This uses a fixed US QWERTY table, which is enough to drive the state machine from raw evdev
+//! events in tests and simple setups. Resolving keysyms through the user's actual layout (e.g.
+//! with `xkbcommon`) is out of scope for this crate.
+
+use crate::{Keysym, ModMask};
+use xkeysym::Keysym as InnerKeysym;
+
+/// `(evdev code, keysym, keysym with Shift)` for the keys of a US QWERTY keyboard.
+///
+/// Keypad keys list their navigation form first, as Shift selects the numeric one with Num Lock
+/// off; see [`Keysym::keypad_with_num_lock`] for choosing by the Num Lock state instead.
+pub(crate) const US_QWERTY: &[(u16, InnerKeysym, InnerKeysym)] = &[
+    (1, InnerKeysym::Escape, InnerKeysym::Escape),
+    (2, InnerKeysym::_1, InnerKeysym::exclam),
+    (3, InnerKeysym::_2, InnerKeysym::at),
+    (4, InnerKeysym::_3, InnerKeysym::numbersign),
+    (5, InnerKeysym::_4, InnerKeysym::dollar),
+    (6, InnerKeysym::_5, InnerKeysym::percent),
+    (7, InnerKeysym::_6, InnerKeysym::asciicircum),
+    (8, InnerKeysym::_7, InnerKeysym::ampersand),
+    (9, InnerKeysym::_8, InnerKeysym::asterisk),
+    (10, InnerKeysym::_9, InnerKeysym::parenleft),
+    (11, InnerKeysym::_0, InnerKeysym::parenright),
+    (12, InnerKeysym::minus, InnerKeysym::underscore),
+    (13, InnerKeysym::equal, InnerKeysym::plus),
+    (14, InnerKeysym::BackSpace, InnerKeysym::BackSpace),
+    (15, InnerKeysym::Tab, InnerKeysym::ISO_Left_Tab),
+    (16, InnerKeysym::q, InnerKeysym::Q),
+    (17, InnerKeysym::w, InnerKeysym::W),
+    (18, InnerKeysym::e, InnerKeysym::E),
+    (19, InnerKeysym::r, InnerKeysym::R),
+    (20, InnerKeysym::t, InnerKeysym::T),
+    (21, InnerKeysym::y, InnerKeysym::Y),
+    (22, InnerKeysym::u, InnerKeysym::U),
+    (23, InnerKeysym::i, InnerKeysym::I),
+    (24, InnerKeysym::o, InnerKeysym::O),
+    (25, InnerKeysym::p, InnerKeysym::P),
+    (26, InnerKeysym::bracketleft, InnerKeysym::braceleft),
+    (27, InnerKeysym::bracketright, InnerKeysym::braceright),
+    (28, InnerKeysym::Return, InnerKeysym::Return),
+    (29, InnerKeysym::Control_L, InnerKeysym::Control_L),
+    (30, InnerKeysym::a, InnerKeysym::A),
+    (31, InnerKeysym::s, InnerKeysym::S),
+    (32, InnerKeysym::d, InnerKeysym::D),
+    (33, InnerKeysym::f, InnerKeysym::F),
+    (34, InnerKeysym::g, InnerKeysym::G),
+    (35, InnerKeysym::h, InnerKeysym::H),
+    (36, InnerKeysym::j, InnerKeysym::J),
+    (37, InnerKeysym::k, InnerKeysym::K),
+    (38, InnerKeysym::l, InnerKeysym::L),
+    (39, InnerKeysym::semicolon, InnerKeysym::colon),
+    (40, InnerKeysym::apostrophe, InnerKeysym::quotedbl),
+    (41, InnerKeysym::grave, InnerKeysym::asciitilde),
+    (42, InnerKeysym::Shift_L, InnerKeysym::Shift_L),
+    (43, InnerKeysym::backslash, InnerKeysym::bar),
+    (44, InnerKeysym::z, InnerKeysym::Z),
+    (45, InnerKeysym::x, InnerKeysym::X),
+    (46, InnerKeysym::c, InnerKeysym::C),
+    (47, InnerKeysym::v, InnerKeysym::V),
+    (48, InnerKeysym::b, InnerKeysym::B),
+    (49, InnerKeysym::n, InnerKeysym::N),
+    (50, InnerKeysym::m, InnerKeysym::M),
+    (51, InnerKeysym::comma, InnerKeysym::less),
+    (52, InnerKeysym::period, InnerKeysym::greater),
+    (53, InnerKeysym::slash, InnerKeysym::question),
+    (54, InnerKeysym::Shift_R, InnerKeysym::Shift_R),
+    (55, InnerKeysym::KP_Multiply, InnerKeysym::KP_Multiply),
+    (56, InnerKeysym::Alt_L, InnerKeysym::Alt_L),
+    (57, InnerKeysym::space, InnerKeysym::space),
+    (58, InnerKeysym::Caps_Lock, InnerKeysym::Caps_Lock),
+    (59, InnerKeysym::F1, InnerKeysym::F1),
+    (60, InnerKeysym::F2, InnerKeysym::F2),
+    (61, InnerKeysym::F3, InnerKeysym::F3),
+    (62, InnerKeysym::F4, InnerKeysym::F4),
+    (63, InnerKeysym::F5, InnerKeysym::F5),
+    (64, InnerKeysym::F6, InnerKeysym::F6),
+    (65, InnerKeysym::F7, InnerKeysym::F7),
+    (66, InnerKeysym::F8, InnerKeysym::F8),
+    (67, InnerKeysym::F9, InnerKeysym::F9),
+    (68, InnerKeysym::F10, InnerKeysym::F10),
+    (69, InnerKeysym::Num_Lock, InnerKeysym::Num_Lock),
+    (70, InnerKeysym::Scroll_Lock, InnerKeysym::Scroll_Lock),
+    (71, InnerKeysym::KP_Home, InnerKeysym::KP_7),
+    (72, InnerKeysym::KP_Up, InnerKeysym::KP_8),
+    (73, InnerKeysym::KP_Prior, InnerKeysym::KP_9),
+    (74, InnerKeysym::KP_Subtract, InnerKeysym::KP_Subtract),
+    (75, InnerKeysym::KP_Left, InnerKeysym::KP_4),
+    (76, InnerKeysym::KP_Begin, InnerKeysym::KP_5),
+    (77, InnerKeysym::KP_Right, InnerKeysym::KP_6),
+    (78, InnerKeysym::KP_Add, InnerKeysym::KP_Add),
+    (79, InnerKeysym::KP_End, InnerKeysym::KP_1),
+    (80, InnerKeysym::KP_Down, InnerKeysym::KP_2),
+    (81, InnerKeysym::KP_Next, InnerKeysym::KP_3),
+    (82, InnerKeysym::KP_Insert, InnerKeysym::KP_0),
+    (83, InnerKeysym::KP_Delete, InnerKeysym::KP_Decimal),
+    (87, InnerKeysym::F11, InnerKeysym::F11),
+    (88, InnerKeysym::F12, InnerKeysym::F12),
+    (96, InnerKeysym::KP_Enter, InnerKeysym::KP_Enter),
+    (97, InnerKeysym::Control_R, InnerKeysym::Control_R),
+    (98, InnerKeysym::KP_Divide, InnerKeysym::KP_Divide),
+    (99, InnerKeysym::Print, InnerKeysym::Print),
+    (100, InnerKeysym::Alt_R, InnerKeysym::Alt_R),
+    (102, InnerKeysym::Home, InnerKeysym::Home),
+    (103, InnerKeysym::Up, InnerKeysym::Up),
+    (104, InnerKeysym::Prior, InnerKeysym::Prior),
+    (105, InnerKeysym::Left, InnerKeysym::Left),
+    (106, InnerKeysym::Right, InnerKeysym::Right),
+    (107, InnerKeysym::End, InnerKeysym::End),
+    (108, InnerKeysym::Down, InnerKeysym::Down),
+    (109, InnerKeysym::Next, InnerKeysym::Next),
+    (110, InnerKeysym::Insert, InnerKeysym::Insert),
+    (111, InnerKeysym::Delete, InnerKeysym::Delete),
+    (119, InnerKeysym::Pause, InnerKeysym::Pause),
+    (125, InnerKeysym::Super_L, InnerKeysym::Super_L),
+    (126, InnerKeysym::Super_R, InnerKeysym::Super_R),
+    (127, InnerKeysym::Menu, InnerKeysym::Menu),
+];
+
+/// The keysym produced by the evdev key `code` on a US QWERTY layout.
+///
+/// `mods` is read as X11 modifier bits: [`ModMask::SHIFT`] selects the shifted symbol, and
+/// [`ModMask::CAPS_LOCK`] inverts that choice for letters only. Returns `None` for codes not in
+/// the table.
+#[must_use]
+pub fn keysym_from_evdev(code: u16, mods: ModMask) -> Option<Keysym> {
+    let (_, plain, shifted) = US_QWERTY.iter().find(|(c, _, _)| *c == code)?;
+    let mut shift = mods.contains(ModMask::SHIFT);
+    if mods.contains(ModMask::CAPS_LOCK) && plain.key_char().is_some_and(char::is_alphabetic) {
+        shift = !shift;
+    }
+    Some(Keysym(if shift { *shifted } else { *plain }))
+}
+
+/// The evdev key code which produces `sym` on a US QWERTY layout, with or without Shift.
+///
+/// Returns `None` for keysyms which can not be typed with a single key of that layout.
+#[must_use]
+pub fn evdev_from_keysym(sym: Keysym) -> Option<u16> {
+    US_QWERTY
+        .iter()
+        .find(|(_, plain, shifted)| *plain == sym.0 || *shifted == sym.0)
+        .map(|(code, _, _)| *code)
+}
+
+/// The state needed to resolve evdev key codes to keysyms: the US QWERTY table of this module
+/// and the modifiers currently in effect.
+///
+/// The caller keeps the modifiers up to date with [`Layout::with_modifiers`], e.g. from
+/// [`State::locked`](crate::state_machine::State::locked) and the Shift keys being held.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Layout {
+    mods: ModMask,
+    skip_repeats: bool,
+}
+
+impl Layout {
+    /// A US QWERTY layout without modifiers, forwarding repeats as presses.
+    #[must_use]
+    pub const fn us_qwerty() -> Self {
+        Layout {
+            mods: ModMask::empty(),
+            skip_repeats: false,
+        }
+    }
+    /// Resolve keysyms with `mods`, read as for [`keysym_from_evdev`].
+    #[must_use]
+    pub const fn with_modifiers(mut self, mods: ModMask) -> Self {
+        self.mods = mods;
+        self
+    }
+    /// Drop auto-repeat events instead of forwarding them as presses, see [`from_evdev`].
+    #[must_use]
+    pub const fn with_skip_repeats(mut self, skip: bool) -> Self {
+        self.skip_repeats = skip;
+        self
+    }
+    /// The modifiers keysyms are resolved with.
+    #[must_use]
+    pub const fn modifiers(&self) -> ModMask {
+        self.mods
+    }
+    /// The keysym produced by the evdev key `code` with this layout's modifiers.
+    #[must_use]
+    pub fn keysym(&self, code: u16) -> Option<Keysym> {
+        keysym_from_evdev(code, self.mods)
+    }
+}
+
+/// Translate an evdev `EV_KEY` event into the `(keysym, released)` pair taken by
+/// [`State::process`](crate::state_machine::State::process).
+///
+/// Returns `None` for events of any other type (`EV_SYN`, `EV_MSC`, ...), for key codes unknown
+/// to `layout` and for values other than 0 (release), 1 (press) and 2 (repeat).
+///
+/// Auto-repeat events (value 2) come out as presses, which is how the state machine expects
+/// them: repeats of a grabbed key keep going to the AT, or are swallowed within
+/// [`State::repeat_delay`](crate::state_machine::State::repeat_delay). Use
+/// [`Layout::with_skip_repeats`] to drop them instead, e.g. when the compositor synthesizes its
+/// own repeats, and [`is_repeat`] to tell them apart from the initial press.
+///
+/// Only available with the `evdev` feature.
+#[cfg(feature = "evdev")]
+#[must_use]
+pub fn from_evdev(event: &::evdev::InputEvent, layout: &Layout) -> Option<(Keysym, bool)> {
+    if event.event_type() != ::evdev::EventType::KEY {
+        return None;
+    }
+    let released = match event.value() {
+        0 => true,
+        1 => false,
+        2 if !layout.skip_repeats => false,
+        _ => return None,
+    };
+    Some((layout.keysym(event.code())?, released))
+}
+
+/// Whether `event` is an evdev auto-repeat of a held key (`EV_KEY` with value 2).
+///
+/// Only available with the `evdev` feature.
+#[cfg(feature = "evdev")]
+#[must_use]
+pub fn is_repeat(event: &::evdev::InputEvent) -> bool {
+    event.event_type() == ::evdev::EventType::KEY && event.value() == 2
+}
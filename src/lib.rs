@@ -18,26 +18,217 @@
 //!
 //! If you fail to do this, the compositor is well within its rights to disregard all messages on this
 //! bus without any further interaction.
+//!
+//! ## Features
+//!
+//! - `async-io` (default): the D-Bus parts of the crate, i.e. [`KeyboardMonitorProxy`] and the
+//!   `client`, `error` and `server` modules, with zbus running on `async-io`, for use with smol,
+//!   async-std or any other executor. Without a runtime feature the crate is `no_std` (but
+//!   needs `alloc`), leaving the keysym types, [`keycode`] and the [`state_machine`].
+//! - `tokio`: the same, with zbus running on tokio instead; calls must then happen within a
+//!   tokio runtime. Disable the default features to avoid building `async-io` as well; zbus
+//!   uses tokio if both are enabled.
+//! - `std`: enabled by both runtime features, and not usable on its own: zbus needs a runtime,
+//!   so enabling it without `async-io` or `tokio` is a compile error. Nothing else in the crate
+//!   depends on a particular runtime.
+//! - `tracing`: emit `trace` level spans and events from
+//!   [`State::process`](state_machine::State::process), describing how each key was classified
+//!   and what was decided for it; and a `warn` level event for key grabs set while they can have
+//!   no effect.
+//! - `testing`: the [`testing`] module, with a mock service to test ATs against over zbus
+//!   peer-to-peer connections; like `std`, it needs `async-io` or `tokio`.
+//! - `arbitrary`: [`arbitrary::Arbitrary`](https://docs.rs/arbitrary) for [`Keysym`],
+//!   [`ModMask`] and [`Keystroke`](state_machine::Keystroke), to fuzz the state machine with
+//!   sequences of `(Keysym, bool)` events.
+//! - `evdev`: [`keycode::from_evdev`], turning `evdev::InputEvent`s read from a device into
+//!   state machine input.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(all(feature = "std", not(any(feature = "async-io", feature = "tokio"))))]
+compile_error!(
+    "the `std` and `testing` features need a runtime for zbus: enable `async-io` or `tokio` as well"
+);
+
+extern crate alloc;
 
+#[cfg(feature = "std")]
+pub mod client;
+#[cfg(feature = "std")]
+pub mod error;
+pub mod keycode;
+pub mod prelude;
+pub mod record;
+#[cfg(feature = "std")]
+pub mod server;
 pub mod state_machine;
 #[cfg(test)]
 mod test;
+#[cfg(feature = "testing")]
+pub mod testing;
 
-use serde::{Deserialize, Serialize};
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use xkeysym::Keysym as InnerKeysym;
+#[cfg(feature = "std")]
 use zbus::proxy;
+#[cfg(feature = "std")]
 use zbus::zvariant::{Signature, Type};
 
-use std::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, Not};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, BitXor, BitXorAssign, Deref, Not};
+use core::str::FromStr;
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(transparent)]
 pub struct Keysym(pub InnerKeysym);
 
 impl Keysym {
-    fn key_char(&self) -> Option<char> {
+    /// The keysym with the given raw value; usable in `const` context, e.g. for static tables of
+    /// key bindings.
+    pub const fn new(raw: u32) -> Keysym {
+        Keysym(InnerKeysym::new(raw))
+    }
+    /// The Unicode character this keysym would produce, if any.
+    ///
+    /// This is the value the state machine reports as `unichar` in emitted key events.
+    ///
+    /// ```
+    /// use freedesktop_a11y_keyboardmonitor::Keysym;
+    /// use xkeysym::Keysym as XKeysym;
+    ///
+    /// assert_eq!(Keysym(XKeysym::a).key_char(), Some('a'));
+    /// assert_eq!(Keysym(XKeysym::Caps_Lock).key_char(), None);
+    /// ```
+    pub fn key_char(&self) -> Option<char> {
         self.0.key_char()
     }
+    /// The keysym directly assigned to `c`, e.g. `a` for `'a'`; `None` for characters which are
+    /// only reachable through the generic Unicode keysym range (`0x0100_0000 + code point`),
+    /// such as most emoji.
+    ///
+    /// For such directly assigned keysyms this is the inverse of [`Keysym::key_char`].
+    ///
+    /// ```
+    /// use freedesktop_a11y_keyboardmonitor::Keysym;
+    /// use xkeysym::Keysym as XKeysym;
+    ///
+    /// assert_eq!(Keysym::from_char('a'), Some(Keysym(XKeysym::a)));
+    /// assert_eq!(Keysym::from_char('🦀'), None);
+    /// ```
+    pub fn from_char(c: char) -> Option<Keysym> {
+        let ks = InnerKeysym::from_char(c);
+        (ks.raw() < 0x0100_0000).then_some(Keysym(ks))
+    }
+    /// Whether this is one of the standard modifier keysyms: `Shift`, `Control`, `Caps_Lock`,
+    /// `Shift_Lock`, `Meta`, `Alt`, `Super` and `Hyper` (left and right), the `ISO_*` level and
+    /// group shifts, latches and locks, `Mode_switch` and `Num_Lock`.
+    ///
+    /// This is independent of the configured global `modifiers` of a
+    /// [`State`](state_machine::State), which may contain any key.
+    ///
+    /// ```
+    /// use freedesktop_a11y_keyboardmonitor::Keysym;
+    /// use xkeysym::Keysym as XKeysym;
+    ///
+    /// assert!(Keysym(XKeysym::Control_R).is_modifier());
+    /// assert!(!Keysym(XKeysym::Insert).is_modifier());
+    /// ```
+    pub fn is_modifier(&self) -> bool {
+        self.0.is_modifier_key()
+    }
+    /// The standard modifier bit of a modifier key, the same for its left and right variants, so
+    /// that e.g. a grab configured with `Control_L` also matches `Control_R`:
+    ///
+    /// | Keysyms                                   | Modifier               |
+    /// |-------------------------------------------|------------------------|
+    /// | `Shift_L`, `Shift_R`                      | [`ModMask::SHIFT`]     |
+    /// | `Caps_Lock`, `Shift_Lock`                 | [`ModMask::CAPS_LOCK`] |
+    /// | `Control_L`, `Control_R`                  | [`ModMask::CONTROL`]   |
+    /// | `Alt_L`, `Alt_R`, `Meta_L`, `Meta_R`      | [`ModMask::ALT`]       |
+    /// | `Num_Lock`                                | [`ModMask::NUM_LOCK`]  |
+    /// | `Super_L`, `Super_R`, `Hyper_L`, `Hyper_R`| [`ModMask::SUPER`]     |
+    ///
+    /// `None` for any other keysym.
+    ///
+    /// ```
+    /// use freedesktop_a11y_keyboardmonitor::{Keysym, ModMask};
+    /// use xkeysym::Keysym as XKeysym;
+    ///
+    /// assert_eq!(Keysym(XKeysym::Control_R).canonical_modifier(), Some(ModMask::CONTROL));
+    /// assert_eq!(Keysym(XKeysym::Insert).canonical_modifier(), None);
+    /// ```
+    pub fn canonical_modifier(&self) -> Option<ModMask> {
+        match self.0 {
+            InnerKeysym::Shift_L | InnerKeysym::Shift_R => Some(ModMask::SHIFT),
+            InnerKeysym::Caps_Lock | InnerKeysym::Shift_Lock => Some(ModMask::CAPS_LOCK),
+            InnerKeysym::Control_L | InnerKeysym::Control_R => Some(ModMask::CONTROL),
+            InnerKeysym::Alt_L | InnerKeysym::Alt_R | InnerKeysym::Meta_L | InnerKeysym::Meta_R => {
+                Some(ModMask::ALT)
+            }
+            InnerKeysym::Num_Lock => Some(ModMask::NUM_LOCK),
+            InnerKeysym::Super_L
+            | InnerKeysym::Super_R
+            | InnerKeysym::Hyper_L
+            | InnerKeysym::Hyper_R => Some(ModMask::SUPER),
+            _ => None,
+        }
+    }
+    /// The keysym a keypad key produces with Num Lock on (`num_lock`) or off, for keypad keys
+    /// which have both a numeric and a navigation form; any other keysym is returned unchanged.
+    ///
+    /// | Num Lock on  | Num Lock off |
+    /// |--------------|--------------|
+    /// | `KP_0`       | `KP_Insert`  |
+    /// | `KP_1`       | `KP_End`     |
+    /// | `KP_2`       | `KP_Down`    |
+    /// | `KP_3`       | `KP_Next`    |
+    /// | `KP_4`       | `KP_Left`    |
+    /// | `KP_5`       | `KP_Begin`   |
+    /// | `KP_6`       | `KP_Right`   |
+    /// | `KP_7`       | `KP_Home`    |
+    /// | `KP_8`       | `KP_Up`      |
+    /// | `KP_9`       | `KP_Prior`   |
+    /// | `KP_Decimal` | `KP_Delete`  |
+    ///
+    /// Shift, which inverts Num Lock for these keys in most layouts, is not taken into account.
+    ///
+    /// ```
+    /// use freedesktop_a11y_keyboardmonitor::Keysym;
+    /// use xkeysym::Keysym as XKeysym;
+    ///
+    /// assert_eq!(Keysym(XKeysym::KP_End).keypad_with_num_lock(true), Keysym(XKeysym::KP_1));
+    /// assert_eq!(Keysym(XKeysym::KP_1).keypad_with_num_lock(false), Keysym(XKeysym::KP_End));
+    /// ```
+    pub fn keypad_with_num_lock(self, num_lock: bool) -> Keysym {
+        const KEYPAD: [(InnerKeysym, InnerKeysym); 11] = [
+            (InnerKeysym::KP_0, InnerKeysym::KP_Insert),
+            (InnerKeysym::KP_1, InnerKeysym::KP_End),
+            (InnerKeysym::KP_2, InnerKeysym::KP_Down),
+            (InnerKeysym::KP_3, InnerKeysym::KP_Next),
+            (InnerKeysym::KP_4, InnerKeysym::KP_Left),
+            (InnerKeysym::KP_5, InnerKeysym::KP_Begin),
+            (InnerKeysym::KP_6, InnerKeysym::KP_Right),
+            (InnerKeysym::KP_7, InnerKeysym::KP_Home),
+            (InnerKeysym::KP_8, InnerKeysym::KP_Up),
+            (InnerKeysym::KP_9, InnerKeysym::KP_Prior),
+            (InnerKeysym::KP_Decimal, InnerKeysym::KP_Delete),
+        ];
+        KEYPAD
+            .iter()
+            .find(|(digit, nav)| self.0 == *digit || self.0 == *nav)
+            .map_or(self, |(digit, nav)| {
+                Keysym(if num_lock { *digit } else { *nav })
+            })
+    }
+}
+/// `NoSymbol` (raw value 0), i.e. "no key".
+impl Default for Keysym {
+    fn default() -> Self {
+        Keysym(InnerKeysym::NoSymbol)
+    }
 }
 impl From<InnerKeysym> for Keysym {
     fn from(iks: InnerKeysym) -> Self {
@@ -45,6 +236,281 @@ impl From<InnerKeysym> for Keysym {
     }
 }
 
+/// Renders the XKB name of the keysym (e.g. `Caps_Lock`), falling back to `U+XXXX` for unnamed
+/// Unicode keysyms, and to the raw hex value otherwise.
+impl fmt::Display for Keysym {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(name) = self.0.name() {
+            return f.write_str(name.strip_prefix("XK_").unwrap_or(name));
+        }
+        match self.key_char() {
+            Some(c) => write!(f, "U+{:04X}", u32::from(c)),
+            None => write!(f, "{:#x}", self.0.raw()),
+        }
+    }
+}
+
+/// Formats the raw keysym value, e.g. `format!("{:#06x}", keysym)` gives `0xffe5` for
+/// `Caps_Lock`, as in the X11 headers.
+impl fmt::LowerHex for Keysym {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.0.raw(), f)
+    }
+}
+/// Formats the raw keysym value, see the [`LowerHex`](fmt::LowerHex) implementation.
+impl fmt::UpperHex for Keysym {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::UpperHex::fmt(&self.0.raw(), f)
+    }
+}
+
+/// Raw keysym ranges (inclusive) which contain every named XKB keysym known to `xkeysym`.
+const NAMED_KEYSYM_RANGES: [(u32, u32); 7] = [
+    (0x0000_0000, 0x0000_ffff),
+    (0x00ff_ffff, 0x00ff_ffff),
+    (0x0100_0100, 0x0100_28ff),
+    (0x1000_0000, 0x1000_ffff),
+    (0x1004_ff00, 0x1004_ffff),
+    (0x1005_ff00, 0x1005_ffff),
+    (0x1008_0000, 0x1008_ffff),
+];
+
+/// Every named keysym, in raw value order.
+fn named_keysyms() -> impl Iterator<Item = (&'static str, InnerKeysym)> {
+    NAMED_KEYSYM_RANGES
+        .iter()
+        .flat_map(|&(start, end)| start..=end)
+        .map(InnerKeysym::new)
+        .filter_map(|ks| Some((ks.name()?.strip_prefix("XK_")?, ks)))
+}
+
+/// The keysym of each XKB name (without the `XK_` prefix), shared by all lookups.
+///
+/// Built on first use, by scanning the [`NAMED_KEYSYM_RANGES`] once (a few hundred thousand
+/// values, in the order of milliseconds); every lookup after that is a hash map access. The
+/// other direction needs no table, as `xkeysym` resolves names of keysyms with a `match`.
+#[cfg(feature = "std")]
+fn keysym_names() -> &'static std::collections::HashMap<&'static str, InnerKeysym> {
+    static NAMES: std::sync::OnceLock<std::collections::HashMap<&'static str, InnerKeysym>> =
+        std::sync::OnceLock::new();
+    NAMES.get_or_init(|| {
+        let mut names = std::collections::HashMap::new();
+        for (name, ks) in named_keysyms() {
+            // keep the lowest value for a name, as the scan without `std` does
+            names.entry(name).or_insert(ks);
+        }
+        names
+    })
+}
+
+#[cfg(feature = "std")]
+fn keysym_from_name(name: &str) -> Option<InnerKeysym> {
+    keysym_names().get(name).copied()
+}
+
+/// Without `std` there is nowhere to cache the names, so every lookup scans the named ranges.
+#[cfg(not(feature = "std"))]
+fn keysym_from_name(name: &str) -> Option<InnerKeysym> {
+    named_keysyms().find(|(n, _)| *n == name).map(|(_, ks)| ks)
+}
+
+/// Error returned when a [`Keysym`] can not be parsed from a string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseKeysymError {
+    /// The input string was empty.
+    Empty,
+    /// The input had a `0x` or `U+` prefix, but the remainder was not a valid hex value.
+    InvalidHex(String),
+    /// The input was neither a known XKB keysym name nor a single printable character.
+    UnknownName(String),
+}
+
+impl fmt::Display for ParseKeysymError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseKeysymError::Empty => f.write_str("empty keysym"),
+            ParseKeysymError::InvalidHex(s) => write!(f, "invalid hex keysym value: {s:?}"),
+            ParseKeysymError::UnknownName(s) => write!(f, "unknown keysym name: {s:?}"),
+        }
+    }
+}
+
+impl core::error::Error for ParseKeysymError {}
+
+/// Error returned when a raw value is not a keysym, see [`Keysym::try_from`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidKeysym(pub u32);
+
+impl fmt::Display for InvalidKeysym {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x} is not a valid keysym", self.0)
+    }
+}
+
+impl core::error::Error for InvalidKeysym {}
+
+/// Accepts legacy keysyms (`0x0000_0000..=0x0000_ffff`), Unicode keysyms
+/// (`0x0100_0000..=0x0110_ffff`, except for surrogate code points) and every other keysym with
+/// an XKB name, such as the `XF86*` vendor keysyms. Unlike [`Keysym::new`], which takes any
+/// value, this rejects values corrupted on the wire.
+///
+/// ```
+/// use freedesktop_a11y_keyboardmonitor::{InvalidKeysym, Keysym};
+/// use xkeysym::Keysym as XKeysym;
+///
+/// assert_eq!(Keysym::try_from(0xffe5), Ok(Keysym(XKeysym::Caps_Lock)));
+/// assert_eq!(Keysym::try_from(0x0300_0000), Err(InvalidKeysym(0x0300_0000)));
+/// ```
+impl TryFrom<u32> for Keysym {
+    type Error = InvalidKeysym;
+    fn try_from(raw: u32) -> Result<Self, Self::Error> {
+        let ks = InnerKeysym::new(raw);
+        let valid = match raw {
+            0x0000_0000..=0x0000_ffff => true,
+            0x0100_0000..=0x0110_ffff => char::from_u32(raw - 0x0100_0000).is_some(),
+            _ => ks.name().is_some(),
+        };
+        if valid {
+            Ok(Keysym(ks))
+        } else {
+            Err(InvalidKeysym(raw))
+        }
+    }
+}
+
+/// Parses XKB keysym names (case-sensitive, e.g. `Caps_Lock`), single printable characters,
+/// `0x`-prefixed raw values and `U+XXXX` Unicode code points.
+///
+/// With the `std` feature, names are looked up in a table built on the first lookup, which
+/// takes a few milliseconds once; without it, each lookup scans all named keysyms.
+impl FromStr for Keysym {
+    type Err = ParseKeysymError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (None, _) => return Err(ParseKeysymError::Empty),
+            (Some(c), None) if !c.is_control() => return Ok(Keysym(InnerKeysym::from_char(c))),
+            _ => {}
+        }
+        if let Some(hex) = s.strip_prefix("0x") {
+            return u32::from_str_radix(hex, 16)
+                .map(|raw| Keysym(InnerKeysym::new(raw)))
+                .map_err(|_| ParseKeysymError::InvalidHex(s.to_string()));
+        }
+        if let Some(hex) = s.strip_prefix("U+") {
+            return u32::from_str_radix(hex, 16)
+                .ok()
+                .and_then(char::from_u32)
+                .map(|c| Keysym(InnerKeysym::from_char(c)))
+                .ok_or_else(|| ParseKeysymError::InvalidHex(s.to_string()));
+        }
+        keysym_from_name(s)
+            .map(Keysym)
+            .ok_or_else(|| ParseKeysymError::UnknownName(s.to_string()))
+    }
+}
+
+/// A [`Keysym`] which (de)serializes as its XKB name (e.g. `"Caps_Lock"`) for human-readable
+/// formats such as configuration files.
+///
+/// Keysyms without a name are serialized as their raw numeric value; both forms are accepted
+/// when deserializing, as is anything [`Keysym::from_str`] understands. The D-Bus wire format
+/// always uses the plain numeric [`Keysym`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct NamedKeysym(pub Keysym);
+
+impl From<Keysym> for NamedKeysym {
+    fn from(ks: Keysym) -> Self {
+        NamedKeysym(ks)
+    }
+}
+impl From<NamedKeysym> for Keysym {
+    fn from(nks: NamedKeysym) -> Self {
+        nks.0
+    }
+}
+
+impl Serialize for NamedKeysym {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0.0.name() {
+            Some(name) => serializer.serialize_str(name.strip_prefix("XK_").unwrap_or(name)),
+            None => serializer.serialize_u32(self.0.0.raw()),
+        }
+    }
+}
+
+struct NamedKeysymVisitor;
+
+impl Visitor<'_> for NamedKeysymVisitor {
+    type Value = NamedKeysym;
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("an XKB keysym name or a raw keysym value")
+    }
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<NamedKeysym, E> {
+        v.parse().map(NamedKeysym).map_err(E::custom)
+    }
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<NamedKeysym, E> {
+        u32::try_from(v)
+            .map(|raw| NamedKeysym(Keysym(InnerKeysym::new(raw))))
+            .map_err(|_| E::invalid_value(de::Unexpected::Unsigned(v), &self))
+    }
+}
+
+impl<'de> Deserialize<'de> for NamedKeysym {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(NamedKeysymVisitor)
+    }
+}
+
+/// Forwards to the wrapped [`xkeysym::Keysym`], so any of its methods can be called without
+/// going through `.0`; this adds nothing on top of the inner type.
+///
+/// ```
+/// use freedesktop_a11y_keyboardmonitor::Keysym;
+/// use xkeysym::Keysym as XKeysym;
+///
+/// assert!(Keysym(XKeysym::Shift_L).is_modifier_key());
+/// assert!(!Keysym(XKeysym::a).is_modifier_key());
+/// ```
+impl Deref for Keysym {
+    type Target = InnerKeysym;
+    fn deref(&self) -> &InnerKeysym {
+        &self.0
+    }
+}
+
+impl AsRef<InnerKeysym> for Keysym {
+    fn as_ref(&self) -> &InnerKeysym {
+        &self.0
+    }
+}
+
+impl PartialEq<InnerKeysym> for Keysym {
+    fn eq(&self, other: &InnerKeysym) -> bool {
+        self.0 == *other
+    }
+}
+
+impl PartialEq<Keysym> for InnerKeysym {
+    fn eq(&self, other: &Keysym) -> bool {
+        *self == other.0
+    }
+}
+
+/// Compares against the raw keysym value.
+impl PartialEq<u32> for Keysym {
+    fn eq(&self, other: &u32) -> bool {
+        self.0.raw() == *other
+    }
+}
+
+impl PartialEq<Keysym> for u32 {
+    fn eq(&self, other: &Keysym) -> bool {
+        *self == other.0.raw()
+    }
+}
+
 impl Not for Keysym {
     type Output = Self;
     fn not(self) -> Self {
@@ -52,20 +518,154 @@ impl Not for Keysym {
     }
 }
 
+/// Keysyms from the Latin-1 range, the function and modifier keys (`0xff00..=0xffff`), or
+/// directly encoded Unicode characters.
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Keysym {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let raw = match u.int_in_range(0..=2)? {
+            0 => u.int_in_range(0x20..=0xff)?,
+            1 => u.int_in_range(0xff00..=0xffff)?,
+            _ => 0x0100_0000 | u.int_in_range(0x100..=0x10_ffff)?,
+        };
+        Ok(Keysym(InnerKeysym::new(raw)))
+    }
+}
+
+#[cfg(feature = "std")]
 impl Type for Keysym {
     const SIGNATURE: &'static Signature = u32::SIGNATURE;
 }
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[derive(
+    Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default,
+)]
 #[repr(transparent)]
 pub struct ModMask(pub InnerKeysym);
 
 impl ModMask {
+    /// `ShiftMask`: bit 0 of the X11 modifier mask.
+    pub const SHIFT: ModMask = ModMask(InnerKeysym::new(1 << 0));
+    /// `LockMask`: bit 1 of the X11 modifier mask.
+    pub const CAPS_LOCK: ModMask = ModMask(InnerKeysym::new(1 << 1));
+    /// `ControlMask`: bit 2 of the X11 modifier mask.
+    pub const CONTROL: ModMask = ModMask(InnerKeysym::new(1 << 2));
+    /// `Mod1Mask`: bit 3 of the X11 modifier mask.
+    pub const ALT: ModMask = ModMask(InnerKeysym::new(1 << 3));
+    /// `Mod2Mask`: bit 4 of the X11 modifier mask.
+    pub const NUM_LOCK: ModMask = ModMask(InnerKeysym::new(1 << 4));
+    /// `Mod4Mask`: bit 6 of the X11 modifier mask.
+    pub const SUPER: ModMask = ModMask(InnerKeysym::new(1 << 6));
+
+    /// The mask with the given raw value; usable in `const` context.
+    pub const fn new(raw: u32) -> ModMask {
+        ModMask(InnerKeysym::new(raw))
+    }
+    /// Combine several masks into one, e.g.
+    /// `ModMask::from_mods(&[ModMask::SHIFT, ModMask::CONTROL])`.
+    pub fn from_mods(mods: &[ModMask]) -> Self {
+        mods.iter().fold(ModMask::empty(), |acc, m| acc | *m)
+    }
+    /// Whether every bit set in `other` is also set in `self`; always true for an empty `other`.
+    pub fn contains(&self, other: ModMask) -> bool {
+        (*self & other) == other
+    }
+    /// Whether any bit set in `other` is also set in `self`; always false for an empty `other`.
+    pub fn intersects(&self, other: ModMask) -> bool {
+        !(*self & other).is_empty()
+    }
+    /// Iterate over each set bit of the mask, yielded as its own single-bit `ModMask`.
+    pub fn iter_mods(&self) -> impl Iterator<Item = ModMask> {
+        let raw = self.0.raw();
+        (0..u32::BITS)
+            .map(|bit| 1 << bit)
+            .filter(move |bit| raw & bit != 0)
+            .map(|bit| ModMask(InnerKeysym::new(bit)))
+    }
+    /// The number of bits set in the mask.
+    pub fn count(&self) -> u32 {
+        self.0.raw().count_ones()
+    }
     pub fn is_empty(&self) -> bool {
         self.0.raw() == 0
     }
-    pub fn empty() -> Self {
-        ModMask(InnerKeysym::from(0))
+    pub const fn empty() -> Self {
+        ModMask(InnerKeysym::new(0))
+    }
+    /// The bits set in `self` but not in `other`, i.e. `self & !other`.
+    pub fn difference(self, other: ModMask) -> Self {
+        ModMask((self.0.raw() & !other.0.raw()).into())
+    }
+    /// The bits set in exactly one of `self` and `other`, i.e. `self ^ other`; e.g. the modifiers
+    /// which changed between two events.
+    pub fn symmetric_difference(self, other: ModMask) -> Self {
+        self ^ other
+    }
+    /// Collapse a mask of a single modifier keysym (as sent with `SetKeyGrabs`) to its standard
+    /// modifier bit, see [`Keysym::canonical_modifier`]; any other mask is returned unchanged.
+    ///
+    /// Masks ORing several keysyms can not be split back into their keysyms, so they are not
+    /// normalized; combine the standard bits with [`ModMask::from_mods`] instead.
+    pub fn normalize(self) -> ModMask {
+        Keysym(self.0).canonical_modifier().unwrap_or(self)
+    }
+    /// The modifier keysyms making up this mask: the representative keysym of each standard
+    /// modifier bit, in bit order, e.g. `[Shift_L, Control_L]` for `SHIFT | CONTROL`.
+    ///
+    /// This is lossy: the mask is [normalized](ModMask::normalize) first, so left and right
+    /// variants both come back as the left one (and `Meta`/`Hyper` as `Alt_L`/`Super_L`).
+    /// `Mod3` and `Mod5` have no keysym and are dropped. Any other mask (raw value from `0x100`
+    /// on) is returned as the single keysym of that value.
+    ///
+    /// ```
+    /// use freedesktop_a11y_keyboardmonitor::{Keysym, ModMask};
+    /// use xkeysym::Keysym as XKeysym;
+    ///
+    /// let ctrl_r = ModMask::empty() | Keysym(XKeysym::Control_R);
+    /// assert_eq!(ctrl_r.to_keysyms(), [Keysym(XKeysym::Control_L)]);
+    /// ```
+    pub fn to_keysyms(&self) -> Vec<Keysym> {
+        const REPRESENTATIVES: [(ModMask, InnerKeysym); 6] = [
+            (ModMask::SHIFT, InnerKeysym::Shift_L),
+            (ModMask::CAPS_LOCK, InnerKeysym::Caps_Lock),
+            (ModMask::CONTROL, InnerKeysym::Control_L),
+            (ModMask::ALT, InnerKeysym::Alt_L),
+            (ModMask::NUM_LOCK, InnerKeysym::Num_Lock),
+            (ModMask::SUPER, InnerKeysym::Super_L),
+        ];
+        let mask = self.normalize();
+        if mask.0.raw() >= 0x100 {
+            return alloc::vec![Keysym(mask.0)];
+        }
+        REPRESENTATIVES
+            .iter()
+            .filter(|(m, _)| mask.contains(*m))
+            .map(|(_, ks)| Keysym(*ks))
+            .collect()
+    }
+    /// Translate an `xkb_mod_mask_t` (e.g. from `xkb_state_serialize_mods`) into the standard
+    /// modifier bits of this crate, using `mapping` to find each modifier's bit.
+    ///
+    /// Bits of modifiers without an index in `mapping` are dropped.
+    pub fn from_xkb_mask(mask: u32, mapping: &ModMapping) -> Self {
+        mapping
+            .pairs()
+            .into_iter()
+            .filter(|(_, index)| index.is_some_and(|i| i < u32::BITS && mask & (1 << i) != 0))
+            .fold(ModMask::empty(), |acc, (m, _)| acc | m)
+    }
+    /// Translate the standard modifier bits of this mask into an `xkb_mod_mask_t`, the inverse of
+    /// [`ModMask::from_xkb_mask`].
+    ///
+    /// Any other bits (e.g. from ORing raw keysyms), and modifiers without an index in `mapping`,
+    /// are dropped.
+    pub fn to_xkb_mask(self, mapping: &ModMapping) -> u32 {
+        mapping
+            .pairs()
+            .into_iter()
+            .filter(|(m, _)| self.contains(*m))
+            .filter_map(|(_, index)| index.filter(|i| *i < u32::BITS))
+            .fold(0, |acc, i| acc | (1 << i))
     }
 }
 
@@ -81,6 +681,12 @@ impl BitAnd<Keysym> for ModMask {
         ModMask((self.0.raw() & rhs.0.raw()).into())
     }
 }
+impl BitOr<Self> for ModMask {
+    type Output = ModMask;
+    fn bitor(self, rhs: Self) -> ModMask {
+        ModMask((self.0.raw() | rhs.0.raw()).into())
+    }
+}
 impl BitOr<Keysym> for ModMask {
     type Output = ModMask;
     fn bitor(self, rhs: Keysym) -> ModMask {
@@ -97,11 +703,161 @@ impl BitAndAssign<Keysym> for ModMask {
         *self = ModMask((self.0.raw() & rhs.0.raw()).into())
     }
 }
+impl BitXor<Self> for ModMask {
+    type Output = ModMask;
+    fn bitxor(self, rhs: Self) -> ModMask {
+        ModMask((self.0.raw() ^ rhs.0.raw()).into())
+    }
+}
+impl BitXor<Keysym> for ModMask {
+    type Output = ModMask;
+    fn bitxor(self, rhs: Keysym) -> ModMask {
+        ModMask((self.0.raw() ^ rhs.0.raw()).into())
+    }
+}
+impl BitXorAssign<Self> for ModMask {
+    fn bitxor_assign(&mut self, rhs: Self) {
+        *self = ModMask((self.0.raw() ^ rhs.0.raw()).into())
+    }
+}
+impl BitXorAssign<Keysym> for ModMask {
+    fn bitxor_assign(&mut self, rhs: Keysym) {
+        *self = ModMask((self.0.raw() ^ rhs.0.raw()).into())
+    }
+}
+
+/// Lists the active modifiers by name, joined with `+` as in `Ctrl+Shift` (the form
+/// [`Keystroke`](state_machine::Keystroke) parses), or `<none>` for the empty mask.
+///
+/// The mask is [normalized](ModMask::normalize) first, so a mask of `Control_R` shows as `Ctrl`.
+/// Masks which are not made of the standard modifier bits (any raw value from `0x100` on) show
+/// as the keysym with that value instead.
+impl fmt::Display for ModMask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const NAMES: [(ModMask, &str); 8] = [
+            (ModMask::CONTROL, "Ctrl"),
+            (ModMask::ALT, "Alt"),
+            (ModMask::SHIFT, "Shift"),
+            (ModMask::SUPER, "Super"),
+            (ModMask::new(1 << 5), "Mod3"),
+            (ModMask::new(1 << 7), "Mod5"),
+            (ModMask::CAPS_LOCK, "CapsLock"),
+            (ModMask::NUM_LOCK, "NumLock"),
+        ];
+        let mask = self.normalize();
+        if mask.is_empty() {
+            return f.write_str("<none>");
+        }
+        if mask.0.raw() >= 0x100 {
+            return Keysym(mask.0).fmt(f);
+        }
+        let mut names = NAMES.iter().filter(|(m, _)| mask.contains(*m));
+        if let Some((_, first)) = names.next() {
+            f.write_str(first)?;
+        }
+        names.try_for_each(|(_, name)| write!(f, "+{name}"))
+    }
+}
+
+/// Formats the raw value of the mask as it is, without [normalizing](ModMask::normalize) it.
+impl fmt::LowerHex for ModMask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.0.raw(), f)
+    }
+}
+/// Formats the raw value of the mask, see the [`LowerHex`](fmt::LowerHex) implementation.
+impl fmt::UpperHex for ModMask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::UpperHex::fmt(&self.0.raw(), f)
+    }
+}
+
+/// The xkb modifier index (`xkb_mod_index_t`) of each standard modifier of [`ModMask`].
+///
+/// Bit positions in an `xkb_mod_mask_t` are defined by the keymap, not fixed like the X11 bits
+/// used by [`ModMask`]: e.g. `Alt` and `Super` are virtual modifiers whose indices depend on the
+/// layout. Look them up with `xkb_keymap_mod_get_index` for the keymap in use;
+/// [`ModMapping::CORE`] only covers keymaps which map them to the core X11 modifiers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub struct ModMapping {
+    /// Index of `Shift`, for [`ModMask::SHIFT`].
+    pub shift: Option<u32>,
+    /// Index of `Lock`, for [`ModMask::CAPS_LOCK`].
+    pub caps_lock: Option<u32>,
+    /// Index of `Control`, for [`ModMask::CONTROL`].
+    pub control: Option<u32>,
+    /// Index of `Alt` (or `Mod1`), for [`ModMask::ALT`].
+    pub alt: Option<u32>,
+    /// Index of `NumLock` (or `Mod2`), for [`ModMask::NUM_LOCK`].
+    pub num_lock: Option<u32>,
+    /// Index of `Super` (or `Mod4`), for [`ModMask::SUPER`].
+    pub logo: Option<u32>,
+}
+
+impl ModMapping {
+    /// The indices of the eight core X11 modifiers (`Shift`, `Lock`, `Control`, `Mod1` to
+    /// `Mod5`), which xkbcommon always assigns first; with `Alt` on `Mod1`, `NumLock` on `Mod2`
+    /// and `Super` on `Mod4`, as in the default keymaps.
+    pub const CORE: ModMapping = ModMapping {
+        shift: Some(0),
+        caps_lock: Some(1),
+        control: Some(2),
+        alt: Some(3),
+        num_lock: Some(4),
+        logo: Some(6),
+    };
+
+    fn pairs(&self) -> [(ModMask, Option<u32>); 6] {
+        [
+            (ModMask::SHIFT, self.shift),
+            (ModMask::CAPS_LOCK, self.caps_lock),
+            (ModMask::CONTROL, self.control),
+            (ModMask::ALT, self.alt),
+            (ModMask::NUM_LOCK, self.num_lock),
+            (ModMask::SUPER, self.logo),
+        ]
+    }
+}
+
+/// ORs together the raw values of all keysyms, e.g. the `modifiers` passed to `SetKeyGrabs`.
+impl FromIterator<Keysym> for ModMask {
+    fn from_iter<I: IntoIterator<Item = Keysym>>(iter: I) -> Self {
+        let mut mask = ModMask::empty();
+        mask.extend(iter);
+        mask
+    }
+}
+
+impl Extend<Keysym> for ModMask {
+    fn extend<I: IntoIterator<Item = Keysym>>(&mut self, iter: I) {
+        for k in iter {
+            *self |= k;
+        }
+    }
+}
+
+/// Either a combination of the X11 modifier bits, or the OR of up to two keysyms, as sent with
+/// `SetKeyGrabs`.
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for ModMask {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        if u.arbitrary()? {
+            return Ok(ModMask(InnerKeysym::new(u.int_in_range(0..=0xff)?)));
+        }
+        let mut mask = ModMask::empty();
+        for _ in 0..u.int_in_range(0..=2)? {
+            mask |= Keysym::arbitrary(u)?;
+        }
+        Ok(mask)
+    }
+}
 
+#[cfg(feature = "std")]
 impl Type for ModMask {
     const SIGNATURE: &'static Signature = u32::SIGNATURE;
 }
 
+#[cfg(feature = "std")]
 #[proxy(
     interface = "org.freedesktop.a11y.KeyboardMonitor",
     default_path = "/org/freedesktop/a11y/Manager",
@@ -134,7 +890,7 @@ pub trait KeyboardMonitor {
         released: bool,
         state: ModMask,
         keysym: Keysym,
-        unichar: char,
+        unichar: u32,
         keycode: u16,
     ) -> zbus::Result<()>;
 }
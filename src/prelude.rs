@@ -0,0 +1,10 @@
+//! The types most users need, for a single glob import:
+//!
+//! ```
+//! use freedesktop_a11y_keyboardmonitor::prelude::*;
+//! ```
+
+#[cfg(feature = "std")]
+pub use crate::KeyboardMonitorProxy;
+pub use crate::state_machine::{KeyEvent, KeyEventType, Keystroke, State};
+pub use crate::{Keysym, ModMask};
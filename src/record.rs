@@ -0,0 +1,108 @@
+//! Record the decisions of a [`State`] and replay them later.
+//!
+//! This is meant for reproducing grab bugs: wrap the state of the input daemon in a
+//! [`StateRecorder`], serialize its [`Trace`] once the bug shows up, and [`replay`] it in a test
+//! to compare the decisions of the current code against the recorded ones.
+
+use crate::Keysym;
+use crate::state_machine::{KeyEventType, State, StateConfig};
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+/// A single input event passed to [`State::process`], and what was decided for it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedEvent {
+    /// The key pressed or released.
+    pub keysym: Keysym,
+    /// If it was a release event.
+    pub release: bool,
+    /// What [`State::process`] returned for it.
+    pub result: KeyEventType,
+}
+
+/// The configuration a recording started from, and the events processed since.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Trace {
+    /// The configuration of the state when the recording started.
+    pub config: StateConfig,
+    /// The events, in the order they were processed.
+    pub events: Vec<RecordedEvent>,
+}
+
+impl Trace {
+    /// The index of the first event for which `results` (e.g. from [`replay`]) differs from the
+    /// recorded decision, or `None` if all of them match.
+    #[must_use]
+    pub fn first_divergence(&self, results: &[KeyEventType]) -> Option<usize> {
+        self.events
+            .iter()
+            .map(|ev| &ev.result)
+            .zip(results)
+            .position(|(recorded, result)| recorded != result)
+            .or_else(|| {
+                (self.events.len() != results.len()).then(|| self.events.len().min(results.len()))
+            })
+    }
+}
+
+/// A [`State`] which logs every processed event into a [`Trace`].
+///
+/// The recording starts from a fresh state built from a [`StateConfig`], so that [`replay`]
+/// can reconstruct it exactly; runtime state such as held keys is not part of a trace.
+#[derive(Debug, Default)]
+pub struct StateRecorder {
+    state: State,
+    trace: Trace,
+}
+
+impl StateRecorder {
+    /// Start recording from a fresh state with the given configuration.
+    #[must_use]
+    pub fn new(config: StateConfig) -> Self {
+        StateRecorder {
+            state: State::from(config.clone()),
+            trace: Trace {
+                config,
+                events: Vec::new(),
+            },
+        }
+    }
+    /// Process an event with [`State::process`], and record the decision.
+    #[must_use]
+    pub fn process(&mut self, keysym: Keysym, release: bool) -> KeyEventType {
+        let result = self.state.process(keysym, release);
+        self.trace.events.push(RecordedEvent {
+            keysym,
+            release,
+            result: result.clone(),
+        });
+        result
+    }
+    /// The recorded state.
+    #[must_use]
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+    /// The trace recorded so far.
+    #[must_use]
+    pub fn trace(&self) -> &Trace {
+        &self.trace
+    }
+    /// Stop recording, returning the trace.
+    #[must_use]
+    pub fn into_trace(self) -> Trace {
+        self.trace
+    }
+}
+
+/// Run the events of `trace` through a fresh state built from its configuration, returning the
+/// decisions in order; compare them with the recorded ones using [`Trace::first_divergence`].
+#[must_use]
+pub fn replay(trace: &Trace) -> Vec<KeyEventType> {
+    let mut state = State::from(trace.config.clone());
+    trace
+        .events
+        .iter()
+        .map(|ev| state.process(ev.keysym, ev.release))
+        .collect()
+}
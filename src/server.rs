@@ -0,0 +1,92 @@
+//! Compositor side of `org.freedesktop.a11y.KeyboardMonitor`.
+//!
+//! [`KeyboardMonitorServer`] implements the D-Bus interface on top of a shared [`State`]; serve
+//! it at `/org/freedesktop/a11y/Manager` and feed input events through the same [`State`] to
+//! decide what to do with each of them.
+
+use crate::state_machine::{KeyEventType, Keystroke, State};
+use crate::{Keysym, ModMask};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use zbus::object_server::SignalEmitter;
+use zbus::{fdo, interface};
+
+/// D-Bus object implementing the `org.freedesktop.a11y.KeyboardMonitor` interface.
+///
+/// Method calls from the AT mutate the wrapped [`State`].
+#[derive(Debug, Default, Clone)]
+pub struct KeyboardMonitorServer {
+    state: Arc<Mutex<State>>,
+}
+
+impl KeyboardMonitorServer {
+    /// Create a server driving the given state; keep a clone of `state` to process input events.
+    pub fn new(state: Arc<Mutex<State>>) -> Self {
+        KeyboardMonitorServer { state }
+    }
+    /// A handle to the state machine driven by this server.
+    pub fn state(&self) -> Arc<Mutex<State>> {
+        Arc::clone(&self.state)
+    }
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[interface(name = "org.freedesktop.a11y.KeyboardMonitor")]
+impl KeyboardMonitorServer {
+    /// GrabKeyboard method
+    fn grab_keyboard(&self) {
+        self.lock().on_grab();
+    }
+
+    /// SetKeyGrabs method
+    ///
+    /// Fails with `org.freedesktop.DBus.Error.InvalidArgs` for conflicting grabs, see
+    /// [`State::on_set_key_grabs`].
+    fn set_key_grabs(
+        &self,
+        modifiers: Vec<Keysym>,
+        keystrokes: Vec<(Keysym, ModMask)>,
+    ) -> fdo::Result<()> {
+        let keystrokes: Vec<Keystroke> = keystrokes.into_iter().map(Keystroke::from).collect();
+        self.lock()
+            .on_set_key_grabs(&modifiers, &keystrokes)
+            .map_err(|e| fdo::Error::InvalidArgs(e.to_string()))
+    }
+
+    /// UngrabKeyboard method
+    ///
+    /// Emits a release for every grabbed key still held, see [`State::flush_pressed`].
+    async fn ungrab_keyboard(
+        &self,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+    ) -> fdo::Result<()> {
+        let flushed = self.lock().on_ungrab();
+        for event in flushed.iter().filter_map(KeyEventType::at_event) {
+            let (released, state, keysym, unichar, keycode) = event.to_signal_args();
+            Self::key_event(&emitter, released, state, keysym, unichar.into(), keycode).await?;
+        }
+        Ok(())
+    }
+
+    /// UnwatchKeyboard method
+    fn unwatch_keyboard(&self) {
+        self.lock().on_unwatch();
+    }
+
+    /// WatchKeyboard method
+    fn watch_keyboard(&self) {
+        self.lock().on_watch();
+    }
+
+    /// KeyEvent signal
+    #[zbus(signal)]
+    pub async fn key_event(
+        emitter: &SignalEmitter<'_>,
+        released: bool,
+        state: ModMask,
+        keysym: Keysym,
+        unichar: u32,
+        keycode: u16,
+    ) -> zbus::Result<()>;
+}
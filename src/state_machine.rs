@@ -16,29 +16,337 @@
     clippy::print_stderr
 )]
 
-use crate::{Keysym as Key, ModMask};
+use crate::{Keysym as Key, ModMask, ParseKeysymError, keycode};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+use core::time::Duration;
+use serde::{Deserialize, Serialize};
+use xkeysym::Keysym as InnerKey;
 
 /// A keystroke struct represents a combination of modifiers and key to be pressed in order to
 /// trigger a [`KeyEvent`] signal to the AT.
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Keystroke {
     /// Mask of modifiers that must be simultaniously pressed in order for the `keysym` field to
     /// trigger a send over to the AT.
     pub modifiers: ModMask,
     /// The key which triggers a [`KeyEvent`] signal to be sent to the AT.
     pub keysym: Key,
+    /// Which of the press and release are sent to the AT; [`Trigger::OnBoth`] unless set with
+    /// [`Keystroke::with_trigger`].
+    #[serde(default)]
+    pub trigger: Trigger,
+    /// Lock modifiers ([`ModMask::CAPS_LOCK`], [`ModMask::NUM_LOCK`]) which must be engaged (see
+    /// [`State::locked`]) for the keystroke to match; other locks may be engaged as well.
+    #[serde(default)]
+    pub required_locks: ModMask,
+}
+
+/// Which edges of a [`Keystroke`] are sent to the AT.
+///
+/// The compositor never sees either edge of a grabbed keystroke, so it does not get a release
+/// without its press (or the other way around); the edge not sent to the AT is
+/// [`KeyEventType::Swallow`]ed instead.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Trigger {
+    /// Only send the press, e.g. for commands which must not fire twice.
+    OnPress,
+    /// Only send the release.
+    OnRelease,
+    /// Send both the press and the release.
+    #[default]
+    OnBoth,
+}
+
+impl Trigger {
+    fn sends(self, release: bool) -> bool {
+        match self {
+            Trigger::OnPress => !release,
+            Trigger::OnRelease => release,
+            Trigger::OnBoth => true,
+        }
+    }
+}
+
+impl Keystroke {
+    /// Create a new keystroke.
+    ///
+    /// Note the argument order: the trigger `keysym` comes _first_, followed by the `modifiers`
+    /// that must be held, matching the `(keysym, modifiers)` tuples of `SetKeyGrabs`.
+    #[must_use]
+    pub fn new(keysym: Key, modifiers: ModMask) -> Self {
+        Keystroke {
+            modifiers,
+            keysym,
+            trigger: Trigger::OnBoth,
+            required_locks: ModMask::empty(),
+        }
+    }
+    /// Only match while the lock modifiers in `locks` are engaged, e.g. [`ModMask::CAPS_LOCK`].
+    #[must_use]
+    pub fn with_required_locks(mut self, locks: ModMask) -> Self {
+        self.required_locks = locks;
+        self
+    }
+    /// Only send the given edges of this keystroke to the AT.
+    #[must_use]
+    pub fn with_trigger(mut self, trigger: Trigger) -> Self {
+        self.trigger = trigger;
+        self
+    }
+    /// Add `m` to the modifiers required by this keystroke.
+    #[must_use]
+    pub fn with_modifier(mut self, m: ModMask) -> Self {
+        self.modifiers = self.modifiers | m;
+        self
+    }
+}
+
+/// The configured [`Keystroke`]s of a [`State`], in insertion order and without duplicates.
+///
+/// Keystrokes are indexed by their keysym, so looking up the candidates for a key event does
+/// not scan the whole list. Dereferences to a slice for read access.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Keystrokes {
+    list: Vec<Keystroke>,
+    by_keysym: BTreeMap<Key, Vec<usize>>,
+}
+
+impl Keystrokes {
+    /// An empty set of keystrokes.
+    #[must_use]
+    pub fn new() -> Self {
+        Keystrokes::default()
+    }
+    /// An empty set of keystrokes with room for `capacity` of them.
+    #[must_use]
+    pub fn with_capacity(capacity: usize) -> Self {
+        Keystrokes {
+            list: Vec::with_capacity(capacity),
+            by_keysym: BTreeMap::new(),
+        }
+    }
+    /// How many keystrokes fit without reallocating.
+    #[must_use]
+    pub fn capacity(&self) -> usize {
+        self.list.capacity()
+    }
+    /// Add `ks` unless it is already present; returns whether it was added.
+    pub fn insert(&mut self, ks: Keystroke) -> bool {
+        if self.list.contains(&ks) {
+            return false;
+        }
+        self.by_keysym
+            .entry(ks.keysym)
+            .or_default()
+            .push(self.list.len());
+        self.list.push(ks);
+        true
+    }
+    /// Remove `ks`; returns whether it was present.
+    ///
+    /// The indices of later keystrokes shift down by one.
+    pub fn remove(&mut self, ks: &Keystroke) -> bool {
+        let Some(index) = self.list.iter().position(|k| k == ks) else {
+            return false;
+        };
+        self.list.remove(index);
+        self.reindex();
+        true
+    }
+    /// Remove all keystrokes.
+    pub fn clear(&mut self) {
+        self.list.clear();
+        self.by_keysym.clear();
+    }
+    /// All keystrokes triggered by `key`, whatever their modifiers.
+    pub fn matching(&self, key: Key) -> impl Iterator<Item = &Keystroke> {
+        self.indices(key).map(|i| &self.list[i])
+    }
+    /// The index of the keystroke triggered by `key` while exactly `modifiers` are held.
+    ///
+    /// Both masks are compared [normalized](ModMask::normalize), so a keystroke configured with
+    /// `Control_L` matches `Control_R` (or [`ModMask::CONTROL`]) as well.
+    ///
+    /// Keystrokes with [`Keystroke::required_locks`] never match; see
+    /// [`Keystrokes::position_with_locks`].
+    #[must_use]
+    pub fn position(&self, key: Key, modifiers: ModMask) -> Option<usize> {
+        self.position_with_locks(key, modifiers, ModMask::empty())
+    }
+    /// As [`Keystrokes::position`], while the lock modifiers in `locked` are engaged.
+    #[must_use]
+    pub fn position_with_locks(
+        &self,
+        key: Key,
+        modifiers: ModMask,
+        locked: ModMask,
+    ) -> Option<usize> {
+        let modifiers = modifiers.normalize();
+        self.indices(key).find(|&i| {
+            self.list[i].modifiers.normalize() == modifiers
+                && locked.contains(self.list[i].required_locks)
+        })
+    }
+    fn indices(&self, key: Key) -> impl Iterator<Item = usize> {
+        self.by_keysym.get(&key).into_iter().flatten().copied()
+    }
+    fn reindex(&mut self) {
+        self.by_keysym.clear();
+        for (i, ks) in self.list.iter().enumerate() {
+            self.by_keysym.entry(ks.keysym).or_default().push(i);
+        }
+    }
+}
+
+impl core::ops::Deref for Keystrokes {
+    type Target = [Keystroke];
+    fn deref(&self) -> &[Keystroke] {
+        &self.list
+    }
+}
+
+impl PartialEq<Vec<Keystroke>> for Keystrokes {
+    fn eq(&self, other: &Vec<Keystroke>) -> bool {
+        self.list == *other
+    }
+}
+
+impl FromIterator<Keystroke> for Keystrokes {
+    fn from_iter<I: IntoIterator<Item = Keystroke>>(iter: I) -> Self {
+        let mut keystrokes = Keystrokes::new();
+        for ks in iter {
+            keystrokes.insert(ks);
+        }
+        keystrokes
+    }
+}
+
+/// Drops duplicates, keeping the first occurrence.
+impl From<Vec<Keystroke>> for Keystrokes {
+    fn from(list: Vec<Keystroke>) -> Self {
+        list.into_iter().collect()
+    }
+}
+
+impl From<Keystrokes> for Vec<Keystroke> {
+    fn from(keystrokes: Keystrokes) -> Self {
+        keystrokes.list
+    }
+}
+
+impl IntoIterator for Keystrokes {
+    type Item = Keystroke;
+    type IntoIter = alloc::vec::IntoIter<Keystroke>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.list.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Keystrokes {
+    type Item = &'a Keystroke;
+    type IntoIter = core::slice::Iter<'a, Keystroke>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.list.iter()
+    }
+}
+
+/// The keys matched by an entry of [`State::patterns`].
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum KeystrokePattern {
+    /// Exactly this key, as for a [`Keystroke`].
+    Exact(Key),
+    /// Any key.
+    Any,
+    /// Any of the listed keys, e.g. all function keys.
+    OneOf(Vec<Key>),
+}
+
+impl KeystrokePattern {
+    /// Whether `key` is matched by this pattern.
+    #[must_use]
+    pub fn matches(&self, key: Key) -> bool {
+        match self {
+            KeystrokePattern::Exact(k) => *k == key,
+            KeystrokePattern::Any => true,
+            KeystrokePattern::OneOf(keys) => keys.contains(&key),
+        }
+    }
+}
+
+impl From<Key> for KeystrokePattern {
+    fn from(key: Key) -> Self {
+        KeystrokePattern::Exact(key)
+    }
+}
+
+/// From the `(keysym, modifiers)` tuples of `SetKeyGrabs`.
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Keystroke {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let trigger = *u.choose(&[Trigger::OnPress, Trigger::OnRelease, Trigger::OnBoth])?;
+        Ok(Keystroke::new(u.arbitrary()?, u.arbitrary()?).with_trigger(trigger))
+    }
+}
+
+impl From<(Key, ModMask)> for Keystroke {
+    fn from((keysym, modifiers): (Key, ModMask)) -> Self {
+        Keystroke::new(keysym, modifiers)
+    }
+}
+
+/// To the `(keysym, modifiers)` tuples of `SetKeyGrabs`, which have no [`Trigger`].
+impl From<Keystroke> for (Key, ModMask) {
+    fn from(ks: Keystroke) -> Self {
+        (ks.keysym, ks.modifiers)
+    }
 }
 
 /// The primary holder of state for all keybindings in the daemon.
 #[derive(Debug, Default)]
+// the flags are independent switches, each set by its own D-Bus method
+#[allow(clippy::struct_excessive_bools)]
 pub struct State {
     /// When set to false, clear all other fields and reset.
     /// Without this flag active, you will always recieve `KeyEvent::ProcessNormally`.
     pub has_client: bool,
+    /// Keep tracking held modifiers while `has_client` is false, so a global grab is already
+    /// active if a client connects while one of `modifiers` is held.
+    ///
+    /// Events are still always processed normally without a client. Modifiers pressed before the
+    /// client connected are recorded in `compositor_modifiers`, so their release reaches the
+    /// compositor as well. Off by default.
+    pub track_without_client: bool,
     /// When set to true, grab _all_ key presses and releases.
+    ///
+    /// This is the grab driven by the global `modifiers`: set by the press of one of them, and
+    /// cleared once none is held any more. Takes precedence over `notify_all`: while both are
+    /// set, every event is [`KeyEventType::SendToAT`] only, except for the release of a key
+    /// pressed before the grab started, which is [`KeyEventType::SendToATAndProcess`] since the
+    /// compositor saw its press.
     pub grab_all: bool,
-    /// Whet set to true, _notify all_ (but do not grab) key presses and releases.
+    /// Like `grab_all`, but requested by the AT through `GrabKeyboard` ([`State::on_grab`]).
+    ///
+    /// Releasing global modifiers does not end this grab; only `UngrabKeyboard`
+    /// ([`State::ungrab`]) does.
+    pub explicit_grab: bool,
+    /// When set, `explicit_grab` ends once this time is reached; see
+    /// [`State::grab_with_timeout`].
+    pub grab_deadline: Option<Duration>,
+    /// When set to true, _notify all_ (but do not grab) key presses and releases.
+    ///
+    /// Grabs take precedence: while `grab_all` or `explicit_grab` is set, or for keys matching
+    /// `modifiers` or `keystrokes`, events are only sent to the AT. See
+    /// [`State::set_notify_all`].
     pub notify_all: bool,
+    /// When set to true, grab the next non-modifier key press and its release, then clear; see
+    /// [`State::grab_once`].
+    pub grab_next_key: bool,
 
     /// Set of modifiers that are used for unconditional grabbing;
     /// if any of these keys are pressed, all other events (until release of
@@ -49,41 +357,353 @@ pub struct State {
     /// will be grabbed, and keys pressed while any of these keys are down
     /// will also be grabbed.
     /// ```
-    pub modifiers: Vec<Key>,
-    /// All modifiers in `modifiers` that have been pressed
     ///
-    /// TODO: key repeat delay
+    /// May be empty, e.g. from `SetKeyGrabs` with only keystrokes: then no key starts a global
+    /// grab, and only `keystrokes` (and `patterns`) are sent to the AT.
+    pub modifiers: Vec<Key>,
+    /// All modifiers in `modifiers`, or used by `keystrokes`, that are currently pressed
     pub pressed_modifiers: ModMask,
+    /// The individual keys making up `pressed_modifiers`, in the order they were pressed.
+    ///
+    /// Kept separately since a mask can not tell which of several overlapping modifiers is still
+    /// held once one of them has been released.
+    pub held_modifiers: Vec<Key>,
+    /// The keys of `held_modifiers` whose press reached the compositor, having been pressed while
+    /// there was no client (see `track_without_client`); their release is
+    /// [`KeyEventType::SendToATAndProcess`], so the compositor does not keep them held.
+    pub compositor_modifiers: Vec<Key>,
+    /// The `Shift_L`/`Shift_R` keys currently held, whether or not they are part of `modifiers`
+    /// or `keystrokes`, for the Shift bit of emitted events and their characters.
+    pub held_shift: Vec<Key>,
+    /// Lock modifiers currently engaged: [`ModMask::CAPS_LOCK`] and [`ModMask::NUM_LOCK`].
+    ///
+    /// The bit flips on each press of `Caps_Lock`/`Num_Lock` which reaches the compositor;
+    /// grabbed presses do not toggle the lock, as the compositor never sees them. It is included
+    /// in the `state` of every emitted [`KeyEvent`].
+    ///
+    /// The Num Lock bit also selects the form of keypad keysyms in emitted events, e.g. `KP_1`
+    /// rather than `KP_End` (see
+    /// [`Keysym::keypad_with_num_lock`](crate::Keysym::keypad_with_num_lock)); initialize it
+    /// from the keyboard's actual lock state. Grabs still match the keysym as it was processed.
+    pub locked: ModMask,
+    /// One-shot modifiers set through [`State::latch`].
+    ///
+    /// They are added to held modifiers (`pressed_modifiers`) both when matching `keystrokes` and
+    /// in the `state` of emitted events, until the next non-modifier key press consumes them,
+    /// even one of `passthrough`. Pressing other modifiers in between keeps the latch pending.
+    pub latched: ModMask,
     /// A list of keystrokes of which may be sent to the AT pending their activation.
-    pub keystrokes: Vec<Keystroke>,
+    ///
+    /// Held modifiers are compared to each keystroke's `modifiers` up to
+    /// [canonical equivalence](crate::Keysym::canonical_modifier): a keystroke requiring
+    /// `Control_L` is also triggered while `Control_R` is held, and one requiring `Alt_L` while
+    /// `Meta_R` is.
+    pub keystrokes: Keystrokes,
+    /// Like `keystrokes`, but each matching a set of keys: while exactly the given modifiers are
+    /// held, any key matched by the pattern is sent to the AT.
+    ///
+    /// Note that [`KeystrokePattern::Any`] also matches modifier keys not part of the mask.
+    pub patterns: Vec<(KeystrokePattern, ModMask)>,
+    /// Keys which are always left to the compositor, even during a global grab; e.g. volume keys.
+    ///
+    /// Global `modifiers` take precedence (a key in both starts a grab as usual), but these keys
+    /// win over `grab_all`, `explicit_grab`, [`State::grab_once`] and matching `keystrokes` or
+    /// `patterns`. While `notify_all` is set they are [`KeyEventType::SendToATAndProcess`], as
+    /// any other key left to the compositor. A key already held as grabbed when it was added
+    /// still has its release sent to the AT.
+    pub passthrough: Vec<Key>,
     /// A list of pressed keys made _after_ global activation;
     /// this is stored so that subsequent releases (which may potentially be released after
-    /// activation) are swallowed.
+    /// activation) are swallowed, by sending them to the AT as [`KeyEventType::SendToAT`].
     ///
     /// Otherwise applications (and the compositor) could receive key up events for keys that were
     /// never pressed in the first place.
     pub pressed: Vec<Key>,
+    /// The [`Trigger`] of each key in `pressed` which was grabbed as a keystroke sending only one
+    /// of its edges to the AT.
+    pub pressed_triggers: Vec<(Key, Trigger)>,
+    /// Keys taken out of `pressed` by [`State::flush_pressed`] while still physically held; their
+    /// repeats and release are swallowed, as the AT already got a release and the compositor
+    /// never saw the press.
+    pub flushed: Vec<Key>,
+    /// When set, repeated presses of an already grabbed key which arrive within this window of
+    /// its initial press are swallowed, so the AT receives a single logical press.
+    ///
+    /// Only has an effect for events fed through [`State::process_at`].
+    pub repeat_delay: Option<Duration>,
+    /// The most recent grabbed press and the time it arrived; used to detect auto-repeat.
+    ///
+    /// Keyboards only auto-repeat the last key pressed, so a single entry is sufficient.
+    pub last_press: Option<(Key, Duration)>,
+    /// Called with the new `pressed_modifiers` whenever processing an event changes them; set
+    /// with [`State::on_modifiers_changed`].
+    pub modifiers_changed: Option<ModifiersCallback>,
+    /// Called with each change of the grab state; set with [`State::set_observer`].
+    pub observer: Option<GrabObserver>,
+}
+
+/// How [`State::decide`] sees a key, see [`State::classify`].
+#[derive(Debug, Clone, Copy)]
+// the flags are the inputs of the decision table, each with its own meaning
+#[allow(clippy::struct_excessive_bools)]
+struct Classified {
+    /// The key is one of the global `modifiers`.
+    is_mod_global: bool,
+    /// The key is a modifier of one of the `keystrokes` or `patterns`.
+    is_mod_local: bool,
+    /// The key's press was sent to the AT, and it is still held.
+    is_already_pressed: bool,
+    /// The keystroke matched by the key with the held modifiers.
+    matched_keystroke: Option<usize>,
+    /// The key matches a keystroke or pattern with the held modifiers.
+    is_keystroke: bool,
+}
+
+/// A change of what a [`State`] grabs, reported to a [`GrabObserver`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GrabEvent {
+    /// A global grab began: `grab_all` or `explicit_grab` was set while neither was.
+    GrabStarted,
+    /// The global grab ended: neither `grab_all` nor `explicit_grab` is set any more.
+    GrabEnded,
+    /// The global `modifiers` or the grabbed `keystrokes` changed.
+    KeystrokesChanged,
+    /// `has_client` was set.
+    ClientConnected,
+    /// `has_client` was cleared.
+    ClientDisconnected,
+}
+
+/// A callback observing changes of the grab state, see [`State::set_observer`].
+pub struct GrabObserver(Box<dyn FnMut(GrabEvent) + Send + Sync>);
+
+impl fmt::Debug for GrabObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("GrabObserver(..)")
+    }
+}
+
+/// A callback observing changes of [`State::pressed_modifiers`], see
+/// [`State::on_modifiers_changed`].
+///
+/// The closure must be `Send + Sync`, so [`State`] stays both and can be shared between async
+/// tasks, e.g. behind the `Arc<Mutex<State>>` of the server.
+pub struct ModifiersCallback(Box<dyn FnMut(ModMask) + Send + Sync>);
+
+impl fmt::Debug for ModifiersCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ModifiersCallback(..)")
+    }
+}
+
+/// Why a set of key grabs was rejected by [`State::apply_key_grabs`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SetKeyGrabsError {
+    /// A keystroke is triggered by a key which is also a global modifier; pressing it would
+    /// start a global grab, so the keystroke could never be told apart.
+    ModifierKeystroke(Keystroke),
+}
+
+impl fmt::Display for SetKeyGrabsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SetKeyGrabsError::ModifierKeystroke(ks) => write!(
+                f,
+                "keystroke {} is also grabbed as a global modifier",
+                ks.keysym
+            ),
+        }
+    }
+}
+
+impl core::error::Error for SetKeyGrabsError {}
+
+/// Why a string could not be parsed as a [`Keystroke`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseKeystrokeError {
+    /// A part was neither a modifier alias nor a keysym.
+    Key(ParseKeysymError),
+    /// Only modifiers were given, but no key.
+    MissingKey,
+    /// More than one part was a key rather than a modifier; holds the second one.
+    MultipleKeys(String),
+}
+
+impl fmt::Display for ParseKeystrokeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseKeystrokeError::Key(e) => e.fmt(f),
+            ParseKeystrokeError::MissingKey => f.write_str("keystroke without a key"),
+            ParseKeystrokeError::MultipleKeys(s) => {
+                write!(f, "keystroke with more than one key: {s:?}")
+            }
+        }
+    }
+}
+
+impl core::error::Error for ParseKeystrokeError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            ParseKeystrokeError::Key(e) => Some(e),
+            ParseKeystrokeError::MissingKey | ParseKeystrokeError::MultipleKeys(_) => None,
+        }
+    }
+}
+
+/// Parses combinations like `Ctrl+Shift+h`: modifiers joined by `+`, followed by the key.
+///
+/// The modifier aliases are `Ctrl` or `Control`, `Shift`, `Alt` or `Meta`, and `Super`
+/// (ignoring ASCII case), giving the standard [`ModMask`] bits. The key is anything
+/// [`Key::from_str`](core::str::FromStr::from_str) accepts, e.g. `h` or `Return`; note that
+/// `H` is the distinct keysym of the capital letter.
+impl FromStr for Keystroke {
+    type Err = ParseKeystrokeError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut modifiers = ModMask::empty();
+        let mut keysym = None;
+        for part in s.split('+').map(str::trim) {
+            let alias = [
+                ("Ctrl", ModMask::CONTROL),
+                ("Control", ModMask::CONTROL),
+                ("Shift", ModMask::SHIFT),
+                ("Alt", ModMask::ALT),
+                ("Meta", ModMask::ALT),
+                ("Super", ModMask::SUPER),
+            ]
+            .into_iter()
+            .find(|(name, _)| part.eq_ignore_ascii_case(name));
+            match (alias, keysym) {
+                (Some((_, m)), _) => modifiers = modifiers | m,
+                (None, None) => keysym = Some(part.parse().map_err(ParseKeystrokeError::Key)?),
+                (None, Some(_)) => return Err(ParseKeystrokeError::MultipleKeys(part.into())),
+            }
+        }
+        keysym
+            .map(|k| Keystroke::new(k, modifiers))
+            .ok_or(ParseKeystrokeError::MissingKey)
+    }
+}
+
+/// Renders the keystroke as its modifiers and key joined with `+`, e.g. `Ctrl+Shift+h`, or just
+/// the key without modifiers; the form parsed by [`Keystroke::from_str`] for standard modifiers.
+///
+/// The `trigger` and `required_locks` are not shown.
+impl fmt::Display for Keystroke {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.modifiers.is_empty() {
+            write!(f, "{}", self.keysym)
+        } else {
+            write!(f, "{}+{}", self.modifiers, self.keysym)
+        }
+    }
+}
+
+impl TryFrom<&str> for Keystroke {
+    type Error = ParseKeystrokeError;
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// The configuration part of a [`State`], to persist it across restarts of the input daemon.
+///
+/// This holds `has_client`, `notify_all`, `track_without_client`, `modifiers`, `keystrokes`,
+/// `patterns`, `passthrough` and `repeat_delay`. Runtime state (`grab_all`, `grab_next_key`,
+/// held and pressed keys, `locked` and `latched` modifiers, and `last_press`) is not persisted,
+/// since the keyboard will have changed in the meantime.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateConfig {
+    /// See [`State::has_client`].
+    pub has_client: bool,
+    /// See [`State::notify_all`].
+    pub notify_all: bool,
+    /// See [`State::track_without_client`].
+    pub track_without_client: bool,
+    /// See [`State::modifiers`].
+    pub modifiers: Vec<Key>,
+    /// See [`State::keystrokes`].
+    pub keystrokes: Vec<Keystroke>,
+    /// See [`State::patterns`].
+    pub patterns: Vec<(KeystrokePattern, ModMask)>,
+    /// See [`State::passthrough`].
+    #[serde(default)]
+    pub passthrough: Vec<Key>,
+    /// See [`State::repeat_delay`].
+    pub repeat_delay: Option<Duration>,
+}
+
+impl From<StateConfig> for State {
+    fn from(config: StateConfig) -> Self {
+        State {
+            has_client: config.has_client,
+            notify_all: config.notify_all,
+            track_without_client: config.track_without_client,
+            modifiers: config.modifiers,
+            keystrokes: config.keystrokes.into(),
+            patterns: config.patterns,
+            passthrough: config.passthrough,
+            repeat_delay: config.repeat_delay,
+            ..State::default()
+        }
+    }
+}
+
+/// A read-only snapshot of what a [`State`] is currently grabbing, see [`State::grab_status`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GrabStatus<'a> {
+    /// Whether all key events are currently grabbed because a global modifier is held.
+    pub grab_all: bool,
+    /// Whether all key events are currently grabbed through `GrabKeyboard`.
+    pub explicit_grab: bool,
+    /// Whether all key events are sent to the AT as well as processed.
+    pub notify_all: bool,
+    /// The tracked modifiers that are currently held.
+    pub pressed_modifiers: ModMask,
+    /// Keys whose press was sent to the AT, and whose release will therefore be as well.
+    pub pressed: &'a [Key],
 }
 
 /// A key event accepted by an on-bus AT.
-#[derive(Clone, Eq, PartialEq, Debug)]
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
 pub struct KeyEvent {
     /// If it was a release event
     release: bool,
-    /// The modmask at the time the event was activated
+    /// The XKB modifier mask at the time the event was activated, made of the standard bits
+    /// (e.g. [`ModMask::CONTROL`]) of the held modifiers and the engaged locks; like X11 key
+    /// events, this is the state _before_ the event itself, so a modifier's own press does not
+    /// include it
     state: ModMask,
     /// The keysym for this event
     keysym: Key,
-    /// Unicode character that would be typed by this action
-    /// TODO: how to calculate?
+    /// Unicode character that would be typed by this action, taking Shift and Caps Lock into
+    /// account; always `None` for releases, which type nothing
     unichar: Option<char>,
-    /// Raw (hardware dependent) keycode
-    /// TODO: how to caluclate?
+    /// The text typed by this action if it is longer than a single `char` (e.g. from a compose
+    /// sequence); `unichar` is `None` then
+    unistr: Option<String>,
+    /// Raw (hardware dependent) keycode, as passed to [`State::process_with_keycode`]
     keycode: u16,
+    /// Set on the event which started or ended a global grab
+    grab_transition: Option<GrabTransition>,
+    /// Index into [`State::keystrokes`] of the keystroke triggered by this press
+    matched_keystroke: Option<usize>,
+    /// When the input event arrived, as passed to [`State::process_at`]
+    timestamp: Option<Duration>,
+}
+
+/// How a [`KeyEvent`] changed the global grab (`grab_all`), see [`KeyEvent::grab_transition`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum GrabTransition {
+    /// The press of a global modifier started the grab.
+    Started,
+    /// The release of the last held global modifier ended the grab.
+    Ended,
 }
-#[cfg(test)]
 impl KeyEvent {
-    /// Create a new `KeyEvent`; restricted to `test` mode only!
+    /// Create a new `KeyEvent`.
+    ///
+    /// Events are normally produced by [`State::process`]; this is for compositors and tests
+    /// which need to build them by hand.
     ///
     /// - `release`: whether the event is a release event or not (false for press event)
     /// - `state`: [`ModMask`] describing the state of the keyboard (modifiers/latches/etc.) use
@@ -91,7 +711,18 @@ impl KeyEvent {
     /// - `keysym`: which key was pressed/released
     /// - `unichar`: if possible, provide the character which this key event would produce: `None`
     ///   for all `release`d keys, and modifiers.
-    /// - `keycode`: raw system-dependent keycode (unused).
+    /// - `keycode`: raw system-dependent keycode.
+    ///
+    /// ```
+    /// use freedesktop_a11y_keyboardmonitor::state_machine::KeyEvent;
+    /// use freedesktop_a11y_keyboardmonitor::{Keysym, ModMask};
+    /// use xkeysym::Keysym as XKeysym;
+    ///
+    /// let event = KeyEvent::new(true, ModMask::empty(), Keysym(XKeysym::H), None, 35);
+    /// assert!(event.release());
+    /// assert_eq!(event.keysym(), Keysym(XKeysym::H));
+    /// assert_eq!(event.unichar(), None);
+    /// ```
     #[must_use]
     pub fn new(
         release: bool,
@@ -105,12 +736,196 @@ impl KeyEvent {
             state,
             keysym,
             unichar,
+            unistr: None,
             keycode,
+            grab_transition: None,
+            matched_keystroke: None,
+            timestamp: None,
+        }
+    }
+    /// Set the text typed by this event, e.g. the result of a compose sequence finished by this
+    /// press, which the state machine can not know about.
+    ///
+    /// A single `char` is stored as [`KeyEvent::unichar`]; longer text is only available through
+    /// [`KeyEvent::unistr`], with `unichar` cleared, as it can not be sent in the D-Bus signal.
+    #[must_use]
+    pub fn with_text(mut self, text: &str) -> Self {
+        let mut chars = text.chars();
+        match (chars.next(), chars.next()) {
+            (c, None) => {
+                self.unichar = c;
+                self.unistr = None;
+            }
+            (Some(_), Some(_)) => {
+                self.unichar = None;
+                self.unistr = Some(text.into());
+            }
+            (None, Some(_)) => unreachable!("no second char without a first"),
+        }
+        self
+    }
+    /// Record when the input event arrived.
+    #[must_use]
+    pub fn with_timestamp(mut self, timestamp: Duration) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+    /// Record the index of the [`Keystroke`] in [`State::keystrokes`] which this press triggered.
+    #[must_use]
+    pub fn with_matched_keystroke(mut self, index: usize) -> Self {
+        self.matched_keystroke = Some(index);
+        self
+    }
+    /// Mark this event as starting or ending a global grab.
+    #[must_use]
+    pub fn with_grab_transition(mut self, transition: GrabTransition) -> Self {
+        self.grab_transition = Some(transition);
+        self
+    }
+    /// Whether `other` describes the same logical key event: the same `release`, `state`,
+    /// `keysym` and `unichar`, whatever their keycode, timestamp and other metadata.
+    ///
+    /// Prefer this over `==` when checking what an AT would be told about an event whose
+    /// hardware details vary, e.g. in tests fed with real input; `==` compares every field.
+    #[must_use]
+    pub fn matches_logical(&self, other: &KeyEvent) -> bool {
+        self.release == other.release
+            && self.state == other.state
+            && self.keysym == other.keysym
+            && self.unichar == other.unichar
+    }
+    /// Whether this is a release (key-up) event.
+    ///
+    /// ```
+    /// use freedesktop_a11y_keyboardmonitor::state_machine::State;
+    /// use freedesktop_a11y_keyboardmonitor::{Keysym, ModMask};
+    /// use xkeysym::Keysym as XKeysym;
+    ///
+    /// let caps = Keysym(XKeysym::Caps_Lock);
+    /// let mut state = State {
+    ///     has_client: true,
+    ///     modifiers: vec![caps],
+    ///     ..State::default()
+    /// };
+    /// let _ = state.press(caps);
+    /// let decision = state.process_with_keycode(Keysym(XKeysym::h), false, 35);
+    /// let event = decision.at_event().expect("h is grabbed while Caps_Lock is held");
+    /// assert!(!event.release());
+    /// // holding Caps_Lock as the AT modifier does not engage Caps Lock
+    /// assert_eq!(event.state(), ModMask::empty());
+    /// assert_eq!(event.keysym(), Keysym(XKeysym::h));
+    /// assert_eq!(event.unichar(), Some('h'));
+    /// assert_eq!(event.keycode(), 35);
+    /// ```
+    #[must_use]
+    pub fn release(&self) -> bool {
+        self.release
+    }
+    /// The modifier mask at the time of the event.
+    #[must_use]
+    pub fn state(&self) -> ModMask {
+        self.state
+    }
+    /// The keysym of the pressed or released key.
+    #[must_use]
+    pub fn keysym(&self) -> Key {
+        self.keysym
+    }
+    /// The Unicode character this key would produce, if any; `None` for releases.
+    #[must_use]
+    pub fn unichar(&self) -> Option<char> {
+        self.unichar
+    }
+    /// The text this key would produce, if any: the [`KeyEvent::unichar`] as a string, or text
+    /// longer than one `char` set with [`KeyEvent::with_text`].
+    #[must_use]
+    pub fn unistr(&self) -> Option<String> {
+        self.unistr
+            .clone()
+            .or_else(|| self.unichar.map(String::from))
+    }
+    /// The raw, hardware dependent keycode.
+    #[must_use]
+    pub fn keycode(&self) -> u16 {
+        self.keycode
+    }
+    /// Whether this event started or ended a global grab; `None` for every other event,
+    /// including keys captured during a grab.
+    ///
+    /// This is not part of the D-Bus signal; compositors may use it e.g. to show a grab
+    /// indicator.
+    #[must_use]
+    pub fn grab_transition(&self) -> Option<GrabTransition> {
+        self.grab_transition
+    }
+    /// The index in [`State::keystrokes`] of the keystroke this press triggered, as it was when
+    /// the event was processed.
+    ///
+    /// `None` for all other events, including the matching release and keys matched by
+    /// [`State::patterns`]. Like [`KeyEvent::grab_transition`], this is not part of the D-Bus
+    /// signal.
+    #[must_use]
+    pub fn matched_keystroke(&self) -> Option<usize> {
+        self.matched_keystroke
+    }
+    /// When the input event arrived, if it was processed through [`State::process_at`]; e.g. to
+    /// measure the latency until the event reaches the AT.
+    ///
+    /// Not part of the D-Bus signal, which has no timestamp.
+    #[must_use]
+    pub fn timestamp(&self) -> Option<Duration> {
+        self.timestamp
+    }
+    /// The arguments of the `KeyEvent` D-Bus signal for this event, in signal order:
+    /// `(released, state, keysym, unichar, keycode)`.
+    ///
+    /// The signal has no notion of a missing character, so `None` is sent as `'\0'`.
+    #[must_use]
+    pub fn to_signal_args(&self) -> (bool, ModMask, Key, char, u16) {
+        (
+            self.release,
+            self.state,
+            self.keysym,
+            self.unichar.unwrap_or('\0'),
+            self.keycode,
+        )
+    }
+    /// Decode the `unichar` argument of a received `KeyEvent` signal, the inverse of
+    /// [`KeyEvent::to_signal_args`].
+    ///
+    /// The wire carries a plain `u32`: `0` (`'\0'`) means "no character" and gives `Ok(None)`.
+    ///
+    /// # Errors
+    ///
+    /// [`InvalidUnichar`] if the value is not a Unicode scalar value, e.g. a surrogate or anything
+    /// above `0x10FFFF`.
+    pub fn decode_unichar(raw: u32) -> Result<Option<char>, InvalidUnichar> {
+        match char::from_u32(raw) {
+            Some('\0') => Ok(None),
+            Some(c) => Ok(Some(c)),
+            None => Err(InvalidUnichar(raw)),
         }
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// A `unichar` received over D-Bus which is not a Unicode scalar value, see
+/// [`KeyEvent::decode_unichar`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidUnichar(pub u32);
+
+impl fmt::Display for InvalidUnichar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid unichar: {:#x} is not a Unicode scalar value",
+            self.0
+        )
+    }
+}
+
+impl core::error::Error for InvalidUnichar {}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 /// The action te perform based on the state of the keyboard handler
 pub enum KeyEventType {
     /// Swallow the event; do not pass to AT, nor process as part of the key handling of the
@@ -119,87 +934,1258 @@ pub enum KeyEventType {
     /// Process the event normally.
     ProcessNormally,
     /// Send the following key event to the AT; do _not_ process though the compositor.
+    ///
+    /// This implies swallowing the event. The release of a key whose press was sent to the AT is
+    /// always sent to the AT too (even once the grab has ended), so the compositor never sees a
+    /// key up without the matching key down.
     SendToAT(KeyEvent),
     /// Process event normally _and_ send to AT
     SendToATAndProcess(KeyEvent),
 }
 
+/// A flat snapshot of a [`KeyEventType`], e.g. to log each decision to a structured sink:
+///
+/// ```
+/// use freedesktop_a11y_keyboardmonitor::state_machine::{KeyDecision, KeyEventType};
+///
+/// let decision = KeyDecision::from(&KeyEventType::Swallow);
+/// assert_eq!(decision.decision, "Swallow");
+/// assert!(!decision.to_at && !decision.to_compositor);
+/// ```
+///
+/// This is for observability only: it is not the D-Bus wire format (see
+/// [`KeyEvent::to_signal_args`]), and can not be turned back into a [`KeyEventType`]; serialize
+/// the [`KeyEventType`] itself to store decisions for replay. The event fields are `None` for
+/// decisions which carry no event.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KeyDecision {
+    /// The name of the variant, e.g. `"SendToAT"`.
+    pub decision: &'static str,
+    /// Whether the event is sent to the AT.
+    pub to_at: bool,
+    /// Whether the compositor processes the event.
+    pub to_compositor: bool,
+    /// Whether the event is a release, see [`KeyEvent::release`].
+    pub release: Option<bool>,
+    /// The raw keysym value, see [`KeyEvent::keysym`].
+    pub keysym: Option<u32>,
+    /// The keysym's name, as shown by its [`Display`](fmt::Display) implementation.
+    pub keysym_name: Option<String>,
+    /// The raw modifier state, see [`KeyEvent::state`].
+    pub state: Option<u32>,
+    /// See [`KeyEvent::unichar`].
+    pub unichar: Option<char>,
+    /// The text typed, see [`KeyEvent::unistr`].
+    pub unistr: Option<String>,
+    /// The hardware keycode, see [`KeyEvent::keycode`].
+    pub keycode: Option<u16>,
+    /// See [`KeyEvent::grab_transition`].
+    pub grab_transition: Option<GrabTransition>,
+    /// See [`KeyEvent::matched_keystroke`].
+    pub matched_keystroke: Option<usize>,
+    /// See [`KeyEvent::timestamp`].
+    pub timestamp: Option<Duration>,
+}
+
+impl From<&KeyEventType> for KeyDecision {
+    fn from(decision: &KeyEventType) -> Self {
+        let ev = decision.at_event();
+        KeyDecision {
+            decision: match decision {
+                KeyEventType::Swallow => "Swallow",
+                KeyEventType::ProcessNormally => "ProcessNormally",
+                KeyEventType::SendToAT(_) => "SendToAT",
+                KeyEventType::SendToATAndProcess(_) => "SendToATAndProcess",
+            },
+            to_at: ev.is_some(),
+            to_compositor: decision.should_process(),
+            release: ev.map(KeyEvent::release),
+            keysym: ev.map(|ev| ev.keysym().0.raw()),
+            keysym_name: ev.map(|ev| ev.keysym().to_string()),
+            state: ev.map(|ev| ev.state().0.raw()),
+            unichar: ev.and_then(KeyEvent::unichar),
+            unistr: ev.and_then(KeyEvent::unistr),
+            keycode: ev.map(KeyEvent::keycode),
+            grab_transition: ev.and_then(KeyEvent::grab_transition),
+            matched_keystroke: ev.and_then(KeyEvent::matched_keystroke),
+            timestamp: ev.and_then(KeyEvent::timestamp),
+        }
+    }
+}
+
+impl KeyEventType {
+    /// The event to send to the AT, for both [`KeyEventType::SendToAT`] and
+    /// [`KeyEventType::SendToATAndProcess`].
+    #[must_use]
+    pub fn at_event(&self) -> Option<&KeyEvent> {
+        match self {
+            KeyEventType::SendToAT(ev) | KeyEventType::SendToATAndProcess(ev) => Some(ev),
+            KeyEventType::Swallow | KeyEventType::ProcessNormally => None,
+        }
+    }
+    /// Whether the compositor should handle the event as usual.
+    #[must_use]
+    pub fn should_process(&self) -> bool {
+        matches!(
+            self,
+            KeyEventType::ProcessNormally | KeyEventType::SendToATAndProcess(_)
+        )
+    }
+    /// Whether the event must be hidden from the compositor (and thereby applications); the
+    /// inverse of [`KeyEventType::should_process`].
+    #[must_use]
+    pub fn should_swallow(&self) -> bool {
+        !self.should_process()
+    }
+    /// Let the compositor process an event sent to the AT as well, for a key whose press the
+    /// compositor has seen.
+    fn and_process(self) -> Self {
+        match self {
+            KeyEventType::SendToAT(ev) => KeyEventType::SendToATAndProcess(ev),
+            decision => decision,
+        }
+    }
+}
+
 impl State {
+    /// A [`Default`] state with room for `keystrokes` grabbed keystrokes and `pressed` keys held
+    /// during a grab, so the hot path does not reallocate.
+    ///
+    /// ATs typically grab a few dozen keystrokes, and rarely more than a handful of keys are held
+    /// at once; e.g. `State::with_capacity(64, 8)`.
+    #[must_use]
+    pub fn with_capacity(keystrokes: usize, pressed: usize) -> State {
+        State {
+            keystrokes: Keystrokes::with_capacity(keystrokes),
+            pressed: Vec::with_capacity(pressed),
+            ..State::default()
+        }
+    }
+    /// Add a keystroke to the list of grabbed keystrokes, unless it is already present.
+    ///
+    /// Returns whether the keystroke was added.
+    pub fn add_keystroke(&mut self, ks: Keystroke) -> bool {
+        let added = self.keystrokes.insert(ks);
+        if added {
+            self.notify(GrabEvent::KeystrokesChanged);
+        }
+        added
+    }
+    /// Remove a keystroke from the list of grabbed keystrokes.
+    ///
+    /// Returns whether the keystroke was present.
+    pub fn remove_keystroke(&mut self, ks: &Keystroke) -> bool {
+        let removed = self.keystrokes.remove(ks);
+        if removed {
+            self.notify(GrabEvent::KeystrokesChanged);
+        }
+        removed
+    }
+    /// Remove all grabbed keystrokes.
+    pub fn clear_keystrokes(&mut self) {
+        if !self.keystrokes.is_empty() {
+            self.keystrokes.clear();
+            self.notify(GrabEvent::KeystrokesChanged);
+        }
+    }
+    /// Replace the global `modifiers` and `keystrokes`, as done by `SetKeyGrabs`.
+    ///
+    /// Duplicate entries are dropped, keeping the first occurrence. A keystroke triggered by one
+    /// of the global modifiers is ambiguous and rejected; the state is left unchanged on error.
+    ///
+    /// Grabs only take effect with `has_client` set, e.g. by a preceding [`State::on_watch`] or
+    /// by using [`State::on_set_key_grabs`] instead; with the `tracing` feature, setting them
+    /// without it logs a warning, as it usually means an AT called the methods out of order.
+    ///
+    /// # Errors
+    ///
+    /// [`SetKeyGrabsError::ModifierKeystroke`] for the first keystroke whose keysym is also
+    /// listed in `modifiers`.
+    pub fn apply_key_grabs(
+        &mut self,
+        modifiers: &[Key],
+        keystrokes: &[Keystroke],
+    ) -> Result<(), SetKeyGrabsError> {
+        self.replace_key_grabs(modifiers, keystrokes)?;
+        self.warn_without_client(
+            modifiers,
+            keystrokes,
+            "they have no effect until one connects",
+        );
+        Ok(())
+    }
+    /// With the `tracing` feature, warn if grabs are set while `has_client` is unset.
+    #[cfg_attr(not(feature = "tracing"), allow(clippy::unused_self, unused_variables))]
+    fn warn_without_client(&self, modifiers: &[Key], keystrokes: &[Keystroke], effect: &str) {
+        #[cfg(feature = "tracing")]
+        if !self.has_client && (!modifiers.is_empty() || !keystrokes.is_empty()) {
+            tracing::warn!("key grabs set without an active client; {effect}");
+        }
+    }
+    /// [`State::apply_key_grabs`], without the warning.
+    fn replace_key_grabs(
+        &mut self,
+        modifiers: &[Key],
+        keystrokes: &[Keystroke],
+    ) -> Result<(), SetKeyGrabsError> {
+        if let Some(ks) = keystrokes.iter().find(|ks| modifiers.contains(&ks.keysym)) {
+            return Err(SetKeyGrabsError::ModifierKeystroke(ks.clone()));
+        }
+        // only compared when somebody is watching
+        let old = self
+            .observer
+            .is_some()
+            .then(|| (self.modifiers.clone(), self.keystrokes.to_vec()));
+        self.modifiers.clear();
+        for m in modifiers {
+            if !self.modifiers.contains(m) {
+                self.modifiers.push(*m);
+            }
+        }
+        self.keystrokes.clear();
+        for ks in keystrokes {
+            self.keystrokes.insert(ks.clone());
+        }
+        if let Some((old_modifiers, old_keystrokes)) = old
+            && (old_modifiers != self.modifiers || old_keystrokes[..] != self.keystrokes[..])
+        {
+            self.notify(GrabEvent::KeystrokesChanged);
+        }
+        Ok(())
+    }
+    /// End any global grab, as done by `UngrabKeyboard`: both `explicit_grab` and a `grab_all`
+    /// started by a global modifier.
+    ///
+    /// Global modifiers which are still held stay tracked, and their releases are swallowed:
+    /// the compositor never saw them being pressed, and the AT no longer asked for them. Pressing
+    /// a global modifier again starts a new grab.
+    pub fn ungrab(&mut self) {
+        self.observed(|state| {
+            state.grab_all = false;
+            state.explicit_grab = false;
+            state.grab_deadline = None;
+        });
+    }
+    /// Start or stop sending every key event to the AT, as done by `WatchKeyboard` and
+    /// `UnwatchKeyboard`.
+    ///
+    /// While watching, events which are not otherwise grabbed become
+    /// [`KeyEventType::SendToATAndProcess`]. Grabs take precedence: global modifiers still start a
+    /// grab, and while `grab_all` is active (or for grabbed keystrokes) events stay
+    /// [`KeyEventType::SendToAT`] only. Stopping does not affect grabs; keys held at that
+    /// moment are released to the compositor alone, which has seen their press anyway.
+    pub fn set_notify_all(&mut self, notify_all: bool) {
+        self.observed(|state| {
+            if notify_all {
+                state.has_client = true;
+            }
+            state.notify_all = notify_all;
+        });
+    }
+    /// Handle a `GrabKeyboard` call: start an `explicit_grab` right away, sending every key event
+    /// to the AT until [`State::on_ungrab`], whatever global modifiers are pressed or released in
+    /// the meantime.
+    ///
+    /// This replaces a pending [`State::grab_with_timeout`]: the grab no longer times out.
+    pub fn on_grab(&mut self) {
+        self.observed(|state| {
+            state.has_client = true;
+            state.explicit_grab = true;
+            state.grab_deadline = None;
+        });
+    }
+    /// Start an `explicit_grab` which ends by itself at `deadline`, e.g. for a modal capture
+    /// which is cancelled if the user does not complete it.
+    ///
+    /// `deadline` is on the clock of the timestamps passed to [`State::process_at`] (e.g. the
+    /// evdev event time), not wall-clock time. The grab ends as soon as an event arrives at or
+    /// after it, which is then handled as if there were no grab. Keys still held from the grab
+    /// are flushed as by [`State::flush_pressed`], so their repeats and releases are swallowed;
+    /// but only [`State::expire_grab`] returns the synthesized releases for the AT. Call it from
+    /// a timer at `deadline`, which also keeps the grab from outliving its deadline just because
+    /// no key is pressed. Events without a timestamp never end the grab.
+    ///
+    /// An ungrab arriving first ([`State::on_ungrab`]) ends the grab right away and cancels the
+    /// deadline, as does a later `GrabKeyboard` ([`State::on_grab`]), which grabs without time
+    /// limit. A grab started by a global modifier is not affected by the deadline.
+    pub fn grab_with_timeout(&mut self, deadline: Duration) {
+        self.observed(|state| {
+            state.has_client = true;
+            state.explicit_grab = true;
+            state.grab_deadline = Some(deadline);
+        });
+    }
+    /// End the grab of [`State::grab_with_timeout`] if its deadline has been reached at `now`.
+    ///
+    /// Returns the releases of the keys still held from the grab, to be sent to the AT, as
+    /// [`State::flush_pressed`] does; empty if the grab is still running, or if a global modifier
+    /// is still grabbing them.
+    #[must_use]
+    pub fn expire_grab(&mut self, now: Duration) -> Vec<KeyEventType> {
+        self.observed(|state| state.end_expired_grab(now))
+    }
+    /// End an `explicit_grab` whose `grab_deadline` has passed at `now`, and flush the keys held
+    /// from it unless another grab is still active; returns the synthesized releases.
+    fn end_expired_grab(&mut self, now: Duration) -> Vec<KeyEventType> {
+        if self.grab_deadline.is_none_or(|deadline| now < deadline) {
+            return Vec::new();
+        }
+        self.explicit_grab = false;
+        self.grab_deadline = None;
+        if self.grabbing() {
+            return Vec::new();
+        }
+        self.flush_pressed()
+    }
+    /// Handle a `SetKeyGrabs` call: replace the grabs with [`State::apply_key_grabs`], and mark
+    /// the client as present once they are accepted.
+    ///
+    /// The protocol expects `WatchKeyboard` or `GrabKeyboard` first; with the `tracing` feature,
+    /// grabs set by a client not marked as present yet log a warning, as with
+    /// [`State::apply_key_grabs`]. They take effect regardless.
+    ///
+    /// # Errors
+    ///
+    /// As [`State::apply_key_grabs`]; neither the grabs nor `has_client` change on error.
+    pub fn on_set_key_grabs(
+        &mut self,
+        modifiers: &[Key],
+        keystrokes: &[Keystroke],
+    ) -> Result<(), SetKeyGrabsError> {
+        self.replace_key_grabs(modifiers, keystrokes)?;
+        self.warn_without_client(
+            modifiers,
+            keystrokes,
+            "SetKeyGrabs should follow WatchKeyboard or GrabKeyboard",
+        );
+        self.observed(|state| state.has_client = true);
+        Ok(())
+    }
+    /// Handle an `UngrabKeyboard` call: [`State::ungrab`], then [`State::flush_pressed`].
+    ///
+    /// Returns the synthesized releases, to be sent to the AT.
+    #[must_use]
+    pub fn on_ungrab(&mut self) -> Vec<KeyEventType> {
+        self.ungrab();
+        self.flush_pressed()
+    }
+    /// Synthesize a release for every grabbed key still held (in `pressed`), and stop tracking
+    /// them.
+    ///
+    /// This prevents stuck keys once the AT is no longer interested in them: the AT gets a
+    /// [`KeyEventType::SendToAT`] release for each key right away (unless its keystroke's
+    /// [`Trigger`] does not send releases), and the physical releases, which would otherwise
+    /// follow at some later time, are swallowed. The compositor sees neither.
+    #[must_use]
+    pub fn flush_pressed(&mut self) -> Vec<KeyEventType> {
+        let state = self.event_state();
+        let pressed = core::mem::take(&mut self.pressed);
+        let events = pressed
+            .iter()
+            .filter(|key| {
+                self.pressed_triggers
+                    .iter()
+                    .all(|(k, t)| k != *key || t.sends(true))
+            })
+            .map(|key| {
+                let key = self.resolve_keypad(*key);
+                KeyEventType::SendToAT(KeyEvent::new(true, state, key, None, 0))
+            })
+            .collect();
+        self.flushed.extend(pressed);
+        self.pressed_triggers.clear();
+        self.last_press = None;
+        events
+    }
+    /// Handle an `UnwatchKeyboard` call: stop notifying, see [`State::set_notify_all`].
+    pub fn on_unwatch(&mut self) {
+        self.set_notify_all(false);
+    }
+    /// Handle a `WatchKeyboard` call: mark the client as present and notify it of every event,
+    /// see [`State::set_notify_all`].
+    pub fn on_watch(&mut self) {
+        self.set_notify_all(true);
+    }
+    /// Send exactly the next non-modifier key press, and its release, to the AT (e.g. for a
+    /// "learn mode" describing keys instead of acting on them); later events are handled as usual.
+    ///
+    /// Modifiers pressed in the meantime are processed normally. If `grab_all` is active when the
+    /// key is pressed, the key is grabbed as part of it and the one-off grab is used up all the
+    /// same.
+    pub fn grab_once(&mut self) {
+        self.observed(|state| state.has_client = true);
+        self.grab_next_key = true;
+    }
+    /// Latch the modifiers in `m`, so they apply to exactly the next non-modifier key press
+    /// (sticky keys).
+    ///
+    /// Latched modifiers accumulate with any modifiers physically held at that time.
+    pub fn latch(&mut self, m: ModMask) {
+        self.latched = self.latched | m;
+    }
+    /// Drop all transient grab state (`grab_all`, `explicit_grab`, `grab_deadline`,
+    /// `grab_next_key`, `pressed`, `flushed`, `pressed_modifiers`, `held_modifiers`,
+    /// `compositor_modifiers`, `held_shift`, `latched` and `last_press`), keeping the configured
+    /// `modifiers` and `keystrokes`.
+    ///
+    /// `has_client` and `notify_all` are left untouched; clear `has_client` as well if the AT
+    /// has gone away for good.
+    pub fn reset_runtime(&mut self) {
+        self.observed(|state| {
+            state.grab_all = false;
+            state.explicit_grab = false;
+            state.grab_deadline = None;
+        });
+        self.grab_next_key = false;
+        self.pressed.clear();
+        self.pressed_triggers.clear();
+        self.flushed.clear();
+        self.pressed_modifiers = ModMask::empty();
+        self.held_modifiers.clear();
+        self.compositor_modifiers.clear();
+        self.held_shift.clear();
+        self.latched = ModMask::empty();
+        self.last_press = None;
+    }
+    /// The global modifiers, see `modifiers`.
+    #[must_use]
+    pub fn modifiers(&self) -> &[Key] {
+        &self.modifiers
+    }
+    /// The grabbed keystrokes, see `keystrokes`.
+    #[must_use]
+    pub fn keystrokes(&self) -> &[Keystroke] {
+        &self.keystrokes
+    }
+    /// One human-readable line for each global modifier and grabbed keystroke, e.g. for a "show
+    /// my shortcuts" view:
+    ///
+    /// ```
+    /// use freedesktop_a11y_keyboardmonitor::Keysym;
+    /// use freedesktop_a11y_keyboardmonitor::state_machine::State;
+    /// use xkeysym::Keysym as XKeysym;
+    ///
+    /// let mut state = State::default();
+    /// state.modifiers.push(Keysym(XKeysym::Caps_Lock));
+    /// state.add_keystroke("Ctrl+h".parse().unwrap());
+    /// assert_eq!(state.describe(), ["modifier Caps_Lock", "keystroke Ctrl+h"]);
+    /// ```
+    #[must_use]
+    pub fn describe(&self) -> Vec<String> {
+        let modifiers = self.modifiers.iter().map(|m| format!("modifier {m}"));
+        let keystrokes = self.keystrokes.iter().map(|ks| format!("keystroke {ks}"));
+        modifiers.chain(keystrokes).collect()
+    }
+    /// The tracked modifiers that are currently held, see `pressed_modifiers`.
+    #[must_use]
+    pub fn pressed_modifiers(&self) -> ModMask {
+        self.pressed_modifiers
+    }
+    /// Call `f` with the new modifier mask every time processing an event changes
+    /// [`State::pressed_modifiers`], e.g. to drive a modifier indicator. Replaces any previous
+    /// callback.
+    ///
+    /// The closure runs inline, on the thread calling [`State::process`] (or one of its
+    /// variants), after the event has been decided and before its decision is returned; keep it
+    /// short, as the compositor's input handling waits for it. It must not call back into this
+    /// state, which is borrowed mutably at that point; send the mask on a channel instead if
+    /// more work is needed. Changes made outside of processing, such as
+    /// [`State::reset_runtime`], do not call it.
+    pub fn on_modifiers_changed(&mut self, f: Box<dyn FnMut(ModMask) + Send + Sync>) {
+        self.modifiers_changed = Some(ModifiersCallback(f));
+    }
+    /// Call `obs` with a [`GrabEvent`] every time a grab starts or ends, the grabbed keystrokes
+    /// change, or the client connects or goes away; e.g. to update a tray indicator. Replaces any
+    /// previous observer.
+    ///
+    /// Events are reported from [`State::process`] (and its variants) and from the methods
+    /// handling the interface calls, such as [`State::on_grab`] or [`State::on_set_key_grabs`].
+    /// Writing to the public fields directly is not observed. As with
+    /// [`State::on_modifiers_changed`], the closure runs inline, while this state is borrowed
+    /// mutably.
+    pub fn set_observer(&mut self, obs: impl FnMut(GrabEvent) + Send + Sync + 'static) {
+        self.observer = Some(GrabObserver(Box::new(obs)));
+    }
+    /// Report `event` to the observer, if any.
+    fn notify(&mut self, event: GrabEvent) {
+        if let Some(GrabObserver(obs)) = &mut self.observer {
+            obs(event);
+        }
+    }
+    /// Run `f`, then report how it changed `has_client` and the global grab to the observer.
+    fn observed<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        let (had_client, was_grabbing) = (self.has_client, self.grabbing());
+        let result = f(self);
+        if !had_client && self.has_client {
+            self.notify(GrabEvent::ClientConnected);
+        }
+        if was_grabbing != self.grabbing() {
+            self.notify_grab_transition();
+        }
+        if had_client && !self.has_client {
+            self.notify(GrabEvent::ClientDisconnected);
+        }
+        result
+    }
+    /// Report the start or end of the global grab, whichever just happened.
+    fn notify_grab_transition(&mut self) {
+        self.notify(if self.grabbing() {
+            GrabEvent::GrabStarted
+        } else {
+            GrabEvent::GrabEnded
+        });
+    }
+    /// Whether [`State::process`] would currently hide this event from the compositor, i.e.
+    /// return [`KeyEventType::SendToAT`] or [`KeyEventType::Swallow`]; without changing any
+    /// state.
+    ///
+    /// This shares its classification of the key with [`State::process`], so the two agree for
+    /// events without a timestamp; a grab past its deadline only ends in
+    /// [`State::process_at`], and repeats are only swallowed there.
+    #[must_use]
+    pub fn is_grabbing(&self, key: Key, release: bool) -> bool {
+        if self.flushed.contains(&key) {
+            return true;
+        }
+        if !self.has_client {
+            return false;
+        }
+        let c = self.classify(key);
+        if self.is_passthrough(key, &c) {
+            return false;
+        }
+        if c.is_already_pressed {
+            return true;
+        }
+        if self.grabbing() {
+            // releases of keys pressed before the grab go to the compositor as well
+            return !release || (c.is_mod_global && !self.reached_compositor(key));
+        }
+        if c.is_mod_global {
+            let held = self.held_modifiers.contains(&key) && !self.reached_compositor(key);
+            return !release || held || self.pressed.contains(&key);
+        }
+        let capture_once = self.grab_next_key && !release && !key.0.is_modifier_key();
+        capture_once || (!c.is_mod_local && !release && c.is_keystroke)
+    }
+    /// What is currently being grabbed; useful for diagnostics, or to show a "grab active"
+    /// indicator.
+    #[must_use]
+    pub fn grab_status(&self) -> GrabStatus<'_> {
+        GrabStatus {
+            grab_all: self.grab_all,
+            explicit_grab: self.explicit_grab,
+            notify_all: self.notify_all,
+            pressed_modifiers: self.pressed_modifiers,
+            pressed: &self.pressed,
+        }
+    }
+    /// A copy of the configuration of this state, e.g. to save it; restore it with
+    /// [`State::from`].
+    #[must_use]
+    pub fn config(&self) -> StateConfig {
+        StateConfig {
+            has_client: self.has_client,
+            notify_all: self.notify_all,
+            track_without_client: self.track_without_client,
+            modifiers: self.modifiers.clone(),
+            keystrokes: self.keystrokes.to_vec(),
+            patterns: self.patterns.clone(),
+            passthrough: self.passthrough.clone(),
+            repeat_delay: self.repeat_delay,
+        }
+    }
+    /// Return to the [`Default`] state, dropping all configuration as well.
+    ///
+    /// This clears `has_client`, so every event is processed normally until a client
+    /// configures the state again. Callbacks set with [`State::on_modifiers_changed`] and
+    /// [`State::set_observer`] are kept.
+    pub fn reset_all(&mut self) {
+        let had_grabs = !self.modifiers.is_empty() || !self.keystrokes.is_empty();
+        self.observed(|state| {
+            *state = State {
+                modifiers_changed: state.modifiers_changed.take(),
+                observer: state.observer.take(),
+                ..State::default()
+            };
+        });
+        if had_grabs {
+            self.notify(GrabEvent::KeystrokesChanged);
+        }
+    }
     /// Process a single event, and produce an enum of behaviours for the compositor to implement.
+    ///
+    /// The decision must be acted on: forwarding [`KeyEventType::SendToAT`] events is up to the
+    /// caller, so ignoring the result is a compile-time warning.
+    ///
+    /// ```compile_fail
+    /// #![deny(unused_must_use)]
+    /// use freedesktop_a11y_keyboardmonitor::state_machine::State;
+    /// use freedesktop_a11y_keyboardmonitor::Keysym;
+    /// use xkeysym::Keysym as XKeysym;
+    ///
+    /// let mut state = State::default();
+    /// state.process(Keysym(XKeysym::a), false);
+    /// ```
+    #[must_use]
     pub fn process(&mut self, key: Key, release: bool) -> KeyEventType {
-        if !self.has_client {
-            return KeyEventType::ProcessNormally;
+        self.process_inner(key, release, 0, None)
+    }
+    /// Process a press of `key`; shorthand for `process(key, false)`.
+    #[must_use]
+    pub fn press(&mut self, key: Key) -> KeyEventType {
+        self.process(key, false)
+    }
+    /// Process a release of `key`; shorthand for `process(key, true)`.
+    #[must_use]
+    pub fn release(&mut self, key: Key) -> KeyEventType {
+        self.process(key, true)
+    }
+    /// Process a batch of `(key, release)` events in order, as [`State::process`] would one at a
+    /// time, and collect the decision for each of them.
+    #[must_use]
+    pub fn process_all(&mut self, events: &[(Key, bool)]) -> Vec<KeyEventType> {
+        events
+            .iter()
+            .map(|&(key, release)| self.process(key, release))
+            .collect()
+    }
+    /// Process a single event carrying the hardware `keycode` it was produced by (e.g. the evdev
+    /// code); the keycode is forwarded to the AT in any emitted [`KeyEvent`].
+    #[must_use]
+    pub fn process_with_keycode(&mut self, key: Key, release: bool, keycode: u16) -> KeyEventType {
+        self.process_inner(key, release, keycode, None)
+    }
+    /// Process a single event which arrived at `timestamp`, and produce an enum of behaviours for
+    /// the compositor to implement.
+    ///
+    /// The timestamp must be monotonic (e.g. the evdev event time); it is used to suppress
+    /// auto-repeat of grabbed keys according to [`State::repeat_delay`], and to end a grab
+    /// whose deadline has passed, see [`State::grab_with_timeout`].
+    #[must_use]
+    pub fn process_at(&mut self, key: Key, release: bool, timestamp: Duration) -> KeyEventType {
+        self.process_inner(key, release, 0, Some(timestamp))
+    }
+    /// Whether a press of `key` at `timestamp` is an auto-repeat of the last grabbed press which
+    /// arrived within [`State::repeat_delay`].
+    fn is_repeat(&self, key: Key, timestamp: Option<Duration>) -> bool {
+        match (self.repeat_delay, timestamp, self.last_press) {
+            (Some(delay), Some(now), Some((last_key, at))) => {
+                last_key == key && now.saturating_sub(at) < delay
+            }
+            _ => false,
+        }
+    }
+    /// Record `key` as a held modifier.
+    fn press_modifier(&mut self, key: Key) {
+        if !self.held_modifiers.contains(&key) {
+            self.held_modifiers.push(key);
+        }
+        self.pressed_modifiers |= key;
+    }
+    /// Record `key` as no longer held, recomputing the modifier mask from the remaining ones.
+    fn release_modifier(&mut self, key: Key) {
+        self.held_modifiers.retain(|k| *k != key);
+        self.compositor_modifiers.retain(|k| *k != key);
+        self.pressed_modifiers = self.held_modifiers.iter().copied().collect();
+    }
+    /// Whether `key` is held as a modifier whose press reached the compositor, see
+    /// `compositor_modifiers`.
+    fn reached_compositor(&self, key: Key) -> bool {
+        self.compositor_modifiers.contains(&key)
+    }
+    /// Update held modifiers for an event processed while there is no client, see
+    /// [`State::track_without_client`].
+    fn track_modifier_without_client(&mut self, key: Key, release: bool, is_mod_global: bool) {
+        let is_mod_local = self.is_local_modifier(key);
+        if !is_mod_global && !is_mod_local {
+            return;
+        }
+        if release {
+            self.release_modifier(key);
+        } else {
+            self.press_modifier(key);
+            if !self.reached_compositor(key) {
+                self.compositor_modifiers.push(key);
+            }
+        }
+        self.grab_all = self.any_global_held();
+    }
+    /// The form of keypad `key` for the current Num Lock state, see
+    /// [`Keysym::keypad_with_num_lock`](crate::Keysym::keypad_with_num_lock).
+    fn resolve_keypad(&self, key: Key) -> Key {
+        key.keypad_with_num_lock(self.locked.contains(ModMask::NUM_LOCK))
+    }
+    /// The character typed by `key` with the current Shift and Caps Lock state.
+    ///
+    /// Keysyms from the unshifted level of a US QWERTY layout (see [`crate::keycode`]) are moved
+    /// to their shifted level while a Shift key is held or latched, or for letters while Caps Lock
+    /// is locked; any other keysym already names its character. The Shift and Caps Lock bits are
+    /// taken from `event_state`, so the character always agrees with the `state` of the event.
+    fn unichar(&self, key: Key) -> Option<char> {
+        let mods = self.event_state() & (ModMask::SHIFT | ModMask::CAPS_LOCK);
+        match keycode::evdev_from_keysym(key) {
+            Some(code) if keycode::keysym_from_evdev(code, ModMask::empty()) == Some(key) => {
+                keycode::keysym_from_evdev(code, mods).and_then(|k| k.key_char())
+            }
+            _ => key.key_char(),
+        }
+    }
+    /// The `state` of emitted events: the XKB modifier bits of the held and latched modifiers,
+    /// any held Shift key (see `held_shift`), and the engaged locks.
+    ///
+    /// Held keys which are not standard modifiers (e.g. `Insert` as a global modifier) have no
+    /// bit, and are left out; as is a latched mask which does not normalize to standard bits.
+    /// The lock bits only come from `locked`: a held `Caps_Lock` (e.g. the AT's own modifier)
+    /// does not mean Caps Lock is engaged.
+    fn event_state(&self) -> ModMask {
+        let latched = self.latched.normalize();
+        let latched = if latched.0.raw() < 0x100 {
+            latched
+        } else {
+            ModMask::empty()
+        };
+        let shift = if self.held_shift.is_empty() {
+            ModMask::empty()
+        } else {
+            ModMask::SHIFT
+        };
+        let locks = ModMask::CAPS_LOCK | ModMask::NUM_LOCK;
+        self.held_modifiers
+            .iter()
+            .filter_map(Key::canonical_modifier)
+            .filter(|m| !locks.contains(*m))
+            .fold(latched | shift | self.locked, |acc, m| acc | m)
+    }
+    /// Record a press or release of `Shift_L`/`Shift_R` in `held_shift`.
+    ///
+    /// Called for every event after its decision, so that a Shift key's own press is not part
+    /// of its `state`, like any other modifier's.
+    fn track_shift(&mut self, key: Key, release: bool) {
+        if !matches!(key.0, InnerKey::Shift_L | InnerKey::Shift_R) {
+            return;
+        }
+        if release {
+            self.held_shift.retain(|k| *k != key);
+        } else if !self.held_shift.contains(&key) {
+            self.held_shift.push(key);
         }
-        let key_event_inner = KeyEvent {
+    }
+    /// The held and latched modifiers, each [normalized](ModMask::normalize) on its own.
+    fn normalized_modifiers(&self) -> ModMask {
+        self.held_modifiers
+            .iter()
+            .map(|k| (ModMask::empty() | *k).normalize())
+            .fold(self.latched.normalize(), |acc, m| acc | m)
+    }
+    /// Whether `key` is matched by any of `patterns` while exactly `modifiers` are active.
+    fn matches_pattern(&self, key: Key, modifiers: ModMask) -> bool {
+        self.patterns
+            .iter()
+            .any(|(p, m)| p.matches(key) && m.normalize() == modifiers.normalize())
+    }
+    /// Whether `key` is one of the modifiers of `keystrokes` or `patterns`.
+    ///
+    /// Masks are ORs of raw keysyms, so the bits of an ordinary key (e.g. `a`) may well be part
+    /// of a mask (e.g. `Insert`); apart from a mask of `key` alone, only standard modifier keys
+    /// are therefore considered part of a mask.
+    fn is_local_modifier(&self, key: Key) -> bool {
+        let own_mask = ModMask::empty() | key;
+        self.keystrokes
+            .iter()
+            .map(|ks| ks.modifiers)
+            .chain(self.patterns.iter().map(|(_, m)| *m))
+            .any(|m| {
+                m == own_mask
+                    || (key.is_modifier() && m | key == m)
+                    || key
+                        .canonical_modifier()
+                        .is_some_and(|c| m.normalize().contains(c))
+            })
+    }
+    /// Drop `key` from the pressed keys, returning whether it was one.
+    ///
+    /// A key held as a grabbed key can become a global modifier in the meantime (or the public
+    /// fields were changed by hand); it is never treated as both, but as the modifier from then on.
+    fn forget_pressed(&mut self, key: Key) -> bool {
+        let was_pressed = self.pressed.contains(&key);
+        self.pressed.retain(|k| *k != key);
+        self.pressed_triggers.retain(|(k, _)| *k != key);
+        was_pressed
+    }
+    /// The event to send to the AT for `key`, in the current modifier state.
+    fn key_event(
+        &self,
+        key: Key,
+        release: bool,
+        keycode: u16,
+        timestamp: Option<Duration>,
+    ) -> KeyEvent {
+        let key = self.resolve_keypad(key);
+        KeyEvent {
             release,
             keysym: key,
-            unichar: key.key_char(),
-            keycode: 0,
-            state: self.pressed_modifiers,
+            unichar: if release { None } else { self.unichar(key) },
+            unistr: None,
+            keycode,
+            state: self.event_state(),
+            grab_transition: None,
+            matched_keystroke: None,
+            timestamp,
+        }
+    }
+    /// Leave an event to the compositor; anything left to the compositor is also shown to a
+    /// watching AT.
+    fn leave_to_compositor(&self, key_event: KeyEvent) -> KeyEventType {
+        if self.notify_all {
+            KeyEventType::SendToATAndProcess(key_event)
+        } else {
+            KeyEventType::ProcessNormally
+        }
+    }
+    /// Whether all key events are grabbed, through either `grab_all` or `explicit_grab`.
+    fn grabbing(&self) -> bool {
+        self.grab_all || self.explicit_grab
+    }
+    /// Whether any of the configured global modifiers is still held.
+    fn any_global_held(&self) -> bool {
+        self.held_modifiers
+            .iter()
+            .any(|k| self.modifiers.contains(k))
+    }
+    /// Update the list of pressed keys for a key that is being sent to the AT.
+    fn track_grabbed(
+        &mut self,
+        key: Key,
+        release: bool,
+        timestamp: Option<Duration>,
+        key_event: KeyEventType,
+    ) -> KeyEventType {
+        self.track_keystroke(key, release, timestamp, key_event, Trigger::OnBoth)
+    }
+    /// Grab the press of `key` as a keystroke (`matched_keystroke`, or one of `patterns` if
+    /// `None`).
+    ///
+    /// `key` is tracked as processed, which is not necessarily the keysym of `key_event`.
+    fn grab_keystroke(
+        &mut self,
+        key: Key,
+        matched_keystroke: Option<usize>,
+        timestamp: Option<Duration>,
+        key_event: &KeyEvent,
+    ) -> KeyEventType {
+        let trigger = matched_keystroke.map_or(Trigger::OnBoth, |i| self.keystrokes[i].trigger);
+        let key_event = KeyEventType::SendToAT(KeyEvent {
+            matched_keystroke,
+            ..key_event.clone()
+        });
+        self.track_keystroke(key, false, timestamp, key_event, trigger)
+    }
+    /// As [`State::track_grabbed`], for a key whose press matched a keystroke with `trigger`;
+    /// the edges it does not send are swallowed, including those of later repeats and release.
+    fn track_keystroke(
+        &mut self,
+        key: Key,
+        release: bool,
+        timestamp: Option<Duration>,
+        key_event: KeyEventType,
+        trigger: Trigger,
+    ) -> KeyEventType {
+        let is_already_pressed = self.pressed.contains(&key);
+        let held_trigger = self
+            .pressed_triggers
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map_or(Trigger::OnBoth, |(_, t)| *t);
+        let trigger = match (is_already_pressed, release) {
+            (false, false) => {
+                self.pressed.push(key);
+                if trigger != Trigger::OnBoth {
+                    self.pressed_triggers.push((key, trigger));
+                }
+                self.last_press = timestamp.map(|t| (key, t));
+                trigger
+            }
+            (true, false) if self.is_repeat(key, timestamp) => return KeyEventType::Swallow,
+            (true, true) => {
+                self.pressed.retain(|k| *k != key);
+                self.pressed_triggers.retain(|(k, _)| *k != key);
+                if self.last_press.is_some_and(|(k, _)| k == key) {
+                    self.last_press = None;
+                }
+                held_trigger
+            }
+            (true, false) => held_trigger,
+            // the press was not grabbed, so it reached the compositor, which must see the
+            // release as well to not leave the key stuck
+            (false, true) => return key_event.and_process(),
         };
-        let key_event = KeyEventType::SendToAT(key_event_inner.clone());
+        if trigger.sends(release) {
+            key_event
+        } else {
+            KeyEventType::Swallow
+        }
+    }
+    fn process_inner(
+        &mut self,
+        key: Key,
+        release: bool,
+        keycode: u16,
+        timestamp: Option<Duration>,
+    ) -> KeyEventType {
+        let decision = self.process_untoggled(key, release, keycode, timestamp);
+        if !release && decision.should_process() {
+            self.toggle_lock(key);
+        }
+        decision
+    }
+    /// [`State::process_inner`], leaving the lock toggled by `key` alone; for
+    /// [`MultiClientState`], where only the combined decision tells whether the compositor saw
+    /// the press.
+    fn process_untoggled(
+        &mut self,
+        key: Key,
+        release: bool,
+        keycode: u16,
+        timestamp: Option<Duration>,
+    ) -> KeyEventType {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("process", %key, release, keycode).entered();
+        let was_grabbing = self.grabbing();
+        if let Some(now) = timestamp {
+            // only `expire_grab` hands the synthesized releases to the AT
+            let _ = self.end_expired_grab(now);
+        }
+        if self.flushed.contains(&key) {
+            if release {
+                self.flushed.retain(|k| *k != key);
+            }
+            if was_grabbing != self.grabbing() {
+                self.notify_grab_transition();
+            }
+            self.track_shift(key, release);
+            return KeyEventType::Swallow;
+        }
+        let old_modifiers = self.pressed_modifiers;
+        let mut decision = self.decide(key, release, keycode, timestamp);
+        self.track_shift(key, release);
+        if old_modifiers != self.pressed_modifiers
+            && let Some(ModifiersCallback(f)) = &mut self.modifiers_changed
+        {
+            f(self.pressed_modifiers);
+        }
+        if was_grabbing != self.grabbing() {
+            if let KeyEventType::SendToAT(ev) | KeyEventType::SendToATAndProcess(ev) = &mut decision
+            {
+                ev.grab_transition = Some(if self.grabbing() {
+                    GrabTransition::Started
+                } else {
+                    GrabTransition::Ended
+                });
+            }
+            self.notify_grab_transition();
+        }
+        #[cfg(feature = "tracing")]
+        tracing::trace!(?decision, "processed key event");
+        decision
+    }
+    /// Flip the lock bit toggled by `key`, if it is a lock key.
+    fn toggle_lock(&mut self, key: Key) {
+        match key.0 {
+            InnerKey::Caps_Lock => self.locked ^= ModMask::CAPS_LOCK,
+            InnerKey::Num_Lock => self.locked ^= ModMask::NUM_LOCK,
+            _ => {}
+        }
+    }
+    /// How `key` relates to the configured grabs and the keys currently held.
+    fn classify(&self, key: Key) -> Classified {
         let is_mod_global = self.modifiers.contains(&key);
-        let any_pressed_mods = !self.pressed_modifiers.is_empty();
-        let is_already_pressed = self.pressed.contains(&key);
-        let is_mod_local = self
+        let normalized = self.normalized_modifiers();
+        let matched_keystroke = self
             .keystrokes
-            .iter()
-            .any(|ks| ks.modifiers | key == ks.modifiers);
-        if self.grab_all && is_mod_global && release {
-            self.grab_all = false;
-            return key_event;
+            .position_with_locks(key, normalized, self.locked);
+        Classified {
+            is_mod_global,
+            is_mod_local: self.is_local_modifier(key),
+            // a global modifier is never tracked as a pressed key, see `forget_pressed`
+            is_already_pressed: !is_mod_global && self.pressed.contains(&key),
+            matched_keystroke,
+            is_keystroke: matched_keystroke.is_some() || self.matches_pattern(key, normalized),
         }
-        if self.grab_all {
-            return key_event;
+    }
+    /// Whether `key` is left to the compositor as one of `passthrough`.
+    fn is_passthrough(&self, key: Key, c: &Classified) -> bool {
+        !c.is_mod_global
+            && !c.is_mod_local
+            && !c.is_already_pressed
+            && self.passthrough.contains(&key)
+    }
+    /// Decide for an event during a global grab: everything goes to the AT, and only the
+    /// releases of keys pressed before the grab to the compositor as well.
+    fn decide_grabbed(
+        &mut self,
+        key: Key,
+        release: bool,
+        is_mod_global: bool,
+        timestamp: Option<Duration>,
+        key_event: KeyEventType,
+    ) -> KeyEventType {
+        match (is_mod_global, release) {
+            // the grab ends once no other global modifier is still held
+            (true, true) => {
+                let reached_compositor = self.reached_compositor(key);
+                self.release_modifier(key);
+                self.grab_all = self.any_global_held();
+                if reached_compositor {
+                    key_event.and_process()
+                } else {
+                    key_event
+                }
+            }
+            (true, false) => {
+                self.press_modifier(key);
+                self.grab_all = true;
+                key_event
+            }
+            (false, _) => self.track_grabbed(key, release, timestamp, key_event),
         }
-        if self.notify_all {
-            return KeyEventType::SendToATAndProcess(key_event_inner);
+    }
+    fn decide(
+        &mut self,
+        key: Key,
+        release: bool,
+        keycode: u16,
+        timestamp: Option<Duration>,
+    ) -> KeyEventType {
+        if !self.has_client {
+            if self.track_without_client {
+                let is_mod_global = self.modifiers.contains(&key);
+                self.track_modifier_without_client(key, release, is_mod_global);
+            }
+            return KeyEventType::ProcessNormally;
         }
-        match (
+        let classified = self.classify(key);
+        let Classified {
+            is_mod_global,
+            is_mod_local,
+            is_already_pressed,
+            matched_keystroke,
+            is_keystroke,
+        } = classified;
+        let was_pressed_as_key = is_mod_global && self.forget_pressed(key);
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
             is_mod_global,
-            any_pressed_mods,
+            is_mod_local,
+            is_keystroke,
             is_already_pressed,
+            release,
+            grab_all = self.grab_all,
+            explicit_grab = self.explicit_grab,
+            notify_all = self.notify_all,
+            "classified key"
+        );
+        let key_event_inner = self.key_event(key, release, keycode, timestamp);
+        // the first non-modifier press consumes any latched modifiers, even a passthrough key
+        if !release && !is_mod_global && !is_mod_local && !key.0.is_modifier_key() {
+            self.latched = ModMask::empty();
+        }
+        if self.is_passthrough(key, &classified) {
+            return self.leave_to_compositor(key_event_inner);
+        }
+        let key_event = KeyEventType::SendToAT(key_event_inner.clone());
+        let capture_once =
+            self.grab_next_key && !release && !is_mod_global && !key.0.is_modifier_key();
+        if capture_once {
+            self.grab_next_key = false;
+        }
+        if self.grabbing() {
+            return self.decide_grabbed(key, release, is_mod_global, timestamp, key_event);
+        }
+        if capture_once {
+            return self.track_grabbed(key, release, timestamp, key_event);
+        }
+        let decision = match (
+            is_mod_global,
             is_mod_local,
+            is_keystroke,
+            is_already_pressed,
             release,
         ) {
             // a global modifier has been pressed,
             // and there are no current mods pressed
             (true, _, _, _, false) => {
                 // add key to mask
-                //self.pressed_modifiers |= key;
+                self.press_modifier(key);
                 self.grab_all = true;
                 key_event
             }
-            // a global modifier has been released
-            // and there it is currently pressed
+            // a global modifier has been released outside of a grab: its grab was cleared by
+            // `ungrab` while it was held, or it was pressed as a grabbed key before becoming a
+            // global modifier. Neither the compositor nor the AT expect this release
+            // any more, unless the compositor saw the press (when there was no client yet).
             (true, _, _, _, true) => {
-                // remove key from mask
-                //self.pressed_modifiers &= !key;
-                self.grab_all = false;
-                key_event
+                let was_held = self.held_modifiers.contains(&key);
+                let reached_compositor = self.reached_compositor(key);
+                self.release_modifier(key);
+                // its press went to the AT, so the release has to follow it there
+                if was_pressed_as_key {
+                    key_event
+                } else if reached_compositor {
+                    key_event.and_process()
+                } else if was_held {
+                    KeyEventType::Swallow
+                } else {
+                    KeyEventType::ProcessNormally
+                }
             }
-            // a key has been pressed (or released),
-            // a global modifer is pressed, and
-            // this is not a repeat key (i.e. it either is part of the pressed keys and is being
-            // released, or it is not part of the pressed keys and is being pressed)
-            (false, true, false, _, false) => {
-                self.pressed.push(key);
-                key_event
+            // a key whose press was sent to the AT has been released (or repeated);
+            // keep sending it to the AT so the compositor never sees half of a key press.
+            (false, _, _, true, _) => self.track_grabbed(key, release, timestamp, key_event),
+            // a modifier used by a local keystroke has been pressed or released;
+            // track it, but leave it to the compositor as well.
+            (false, true, _, false, false) => {
+                self.press_modifier(key);
+                KeyEventType::ProcessNormally
             }
-            (false, true, true, _, true) => {
-                self.pressed.retain(|k| *k != key);
-                key_event
+            (false, true, _, false, true) => {
+                self.release_modifier(key);
+                KeyEventType::ProcessNormally
+            }
+            // a local keystroke has been pressed while exactly its modifiers are held
+            (false, false, true, false, false) => {
+                self.grab_keystroke(key, matched_keystroke, timestamp, &key_event_inner)
+            }
+            (false, false, _, false, _) => KeyEventType::ProcessNormally,
+        };
+        debug_assert!(
+            !(is_mod_global && self.pressed.contains(&key)),
+            "global modifier {key} tracked as a pressed key"
+        );
+        if decision == KeyEventType::ProcessNormally {
+            return self.leave_to_compositor(key_event_inner);
+        }
+        decision
+    }
+}
+
+/// Identifies an AT in a [`MultiClientState`], e.g. by the unique D-Bus name of the sender.
+pub type ClientId = String;
+
+/// Grab state for any number of ATs monitoring the keyboard at once; [`State`] handles a single
+/// client.
+///
+/// Each client has its own [`State`], driven by its method calls through
+/// [`MultiClientState::client_mut`], and every input event goes through all of them: a key is
+/// hidden from the compositor if any client grabs it, and sent to every client whose state
+/// wants to see it.
+#[derive(Debug, Default)]
+pub struct MultiClientState {
+    clients: BTreeMap<ClientId, State>,
+    /// Keys hidden from the compositor by a removed client while they were pressed; their
+    /// repeats and release stay hidden, see [`MultiClientState::remove_client`].
+    orphaned: Vec<Key>,
+}
+
+/// The combined decisions of all clients of a [`MultiClientState`] for an input event.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MultiClientDecision {
+    /// The decision of each client.
+    pub decisions: BTreeMap<ClientId, KeyEventType>,
+    /// Set for the repeats and release of a key whose press was hidden by a client removed in
+    /// the meantime; these are hidden from the compositor whatever the `decisions`.
+    pub orphaned: bool,
+}
+
+impl MultiClientDecision {
+    /// Whether the compositor should process the event, i.e. no client grabs it and it is not
+    /// `orphaned`; also `true` when there are no clients.
+    #[must_use]
+    pub fn should_process(&self) -> bool {
+        !self.orphaned && self.decisions.values().all(KeyEventType::should_process)
+    }
+    /// The events to send, along with the client to send each of them to.
+    pub fn at_events(&self) -> impl Iterator<Item = (&ClientId, &KeyEvent)> {
+        self.decisions
+            .iter()
+            .filter_map(|(client, decision)| Some((client, decision.at_event()?)))
+    }
+}
+
+impl MultiClientState {
+    /// The state of `client`, adding it with a default [`State`] if it is not known yet.
+    pub fn client_mut(&mut self, client: &ClientId) -> &mut State {
+        self.clients.entry(client.clone()).or_default()
+    }
+    /// The state of `client`, if it is known.
+    #[must_use]
+    pub fn client(&self, client: &ClientId) -> Option<&State> {
+        self.clients.get(client)
+    }
+    /// The known clients, in order.
+    pub fn clients(&self) -> impl Iterator<Item = &ClientId> {
+        self.clients.keys()
+    }
+    /// Forget a client, e.g. once its name has vanished from the bus, returning its state.
+    ///
+    /// The compositor never saw the presses of the keys the client still holds grabbed (its
+    /// `pressed` and `flushed` keys, and held global `modifiers` not in
+    /// `compositor_modifiers`), so their repeats and releases stay hidden from it: the
+    /// remaining clients still get to decide on them, but the combined decision is
+    /// [`orphaned`](MultiClientDecision::orphaned).
+    pub fn remove_client(&mut self, client: &ClientId) -> Option<State> {
+        let state = self.clients.remove(client)?;
+        let held_globals = state
+            .held_modifiers
+            .iter()
+            .filter(|k| state.modifiers.contains(k) && !state.compositor_modifiers.contains(k));
+        for key in state
+            .pressed
+            .iter()
+            .chain(&state.flushed)
+            .chain(held_globals)
+        {
+            if !self.orphaned.contains(key) {
+                self.orphaned.push(*key);
+            }
+        }
+        Some(state)
+    }
+    /// Whether any client would grab the event, see [`State::is_grabbing`].
+    #[must_use]
+    pub fn is_grabbing(&self, key: Key, release: bool) -> bool {
+        self.orphaned.contains(&key)
+            || self
+                .clients
+                .values()
+                .any(|state| state.is_grabbing(key, release))
+    }
+    /// Run an input event through the state of every client, see [`State::process`].
+    ///
+    /// Lock keys toggle the `locked` state of every client if the compositor processes their
+    /// press, i.e. by the combined decision rather than that of each client.
+    #[must_use]
+    pub fn process(&mut self, key: Key, release: bool) -> MultiClientDecision {
+        let orphaned = self.orphaned.contains(&key);
+        if orphaned && release {
+            self.orphaned.retain(|k| *k != key);
+        }
+        let decision = MultiClientDecision {
+            decisions: self
+                .clients
+                .iter_mut()
+                .map(|(client, state)| {
+                    let decision = state.process_untoggled(key, release, 0, None);
+                    (client.clone(), decision)
+                })
+                .collect(),
+            orphaned,
+        };
+        if !release && decision.should_process() {
+            for state in self.clients.values_mut() {
+                state.toggle_lock(key);
             }
-            // repeat keys while global grab is on;
-            // it is up to the AT how to deal with such events, but no modification of the state
-            // will occur.
-            //
-            // i.e. the release is true while the item is not in the pressed keys, or the release
-            // is false while the item is already in the list
-            (false, false, false, false, _) => KeyEventType::ProcessNormally,
-            (false, false, _, _, _)
-            | (false, true, false, _, true)
-            | (false, true, true, _, false) => key_event,
         }
+        decision
     }
 }
@@ -1,7 +1,45 @@
-use crate::state_machine::{KeyEvent, KeyEventType, Keystroke, State};
+extern crate std;
+
+use crate::state_machine::{
+    GrabTransition, KeyEvent, KeyEventType, Keystroke, SetKeyGrabsError, State, Trigger,
+};
 use crate::{Keysym, ModMask};
+use alloc::boxed::Box;
+use alloc::format;
+// `traced_test` expands to code naming `String`
+#[cfg(feature = "tracing")]
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec;
+use alloc::vec::Vec;
 use xkeysym::Keysym as XKeysym;
 
+/// Assert that each decision has the expected variant, and an event matching the expected one
+/// by [`KeyEvent::matches_logical`] with the same grab transition and matched keystroke: every
+/// field but the keycode and timestamp.
+#[track_caller]
+fn assert_logical(results: &[KeyEventType], expected: &[KeyEventType]) {
+    assert_eq!(
+        results.len(),
+        expected.len(),
+        "Expected results do not match the running of the state machine!"
+    );
+    for (i, (result, expected)) in results.iter().zip(expected).enumerate() {
+        let same_event = match (result.at_event(), expected.at_event()) {
+            (Some(result), Some(expected)) => {
+                result.matches_logical(expected)
+                    && result.grab_transition() == expected.grab_transition()
+                    && result.matched_keystroke() == expected.matched_keystroke()
+            }
+            (result, expected) => result.is_none() && expected.is_none(),
+        };
+        assert!(
+            same_event && core::mem::discriminant(result) == core::mem::discriminant(expected),
+            "event {i}: expected {expected:?}, got {result:?}"
+        );
+    }
+}
+
 #[test]
 fn test_global_standard_keybind() {
     let mut state = State::default();
@@ -21,13 +59,10 @@ fn test_global_standard_keybind() {
     let expected_results: Vec<KeyEventType> = vec![
         KeyEventType::ProcessNormally,
         KeyEventType::ProcessNormally,
-        KeyEventType::SendToAT(KeyEvent::new(
-            false,
-            ModMask::empty(),
-            XKeysym::Caps_Lock.into(),
-            None,
-            0,
-        )),
+        KeyEventType::SendToAT(
+            KeyEvent::new(false, ModMask::empty(), XKeysym::Caps_Lock.into(), None, 0)
+                .with_grab_transition(GrabTransition::Started),
+        ),
         KeyEventType::SendToAT(KeyEvent::new(
             false,
             ModMask::empty(),
@@ -39,16 +74,13 @@ fn test_global_standard_keybind() {
             true,
             ModMask::empty(),
             XKeysym::H.into(),
-            Some('H'),
-            0,
-        )),
-        KeyEventType::SendToAT(KeyEvent::new(
-            true,
-            ModMask::empty(),
-            XKeysym::Caps_Lock.into(),
             None,
             0,
         )),
+        KeyEventType::SendToAT(
+            KeyEvent::new(true, ModMask::empty(), XKeysym::Caps_Lock.into(), None, 0)
+                .with_grab_transition(GrabTransition::Ended),
+        ),
         KeyEventType::ProcessNormally,
         KeyEventType::ProcessNormally,
     ];
@@ -56,10 +88,7 @@ fn test_global_standard_keybind() {
     for ev in events {
         results.push(state.process(Keysym(ev.0), ev.1));
     }
-    assert_eq!(
-        results, expected_results,
-        "Expected results do not match the running of the state machine!"
-    );
+    assert_logical(&results, &expected_results);
 }
 
 #[test]
@@ -71,8 +100,10 @@ fn test_global_non_standard_keybind() {
     let local_keys = vec![Keystroke {
         modifiers: ModMask::empty(),
         keysym: XKeysym::F.into(),
+        trigger: Trigger::OnBoth,
+        required_locks: ModMask::empty(),
     }];
-    state.keystrokes = local_keys;
+    state.keystrokes = local_keys.into();
     let events = vec![
         (XKeysym::F, false),
         (XKeysym::F, true),
@@ -84,15 +115,21 @@ fn test_global_non_standard_keybind() {
         (XKeysym::F, true),
     ];
     let expected_results: Vec<KeyEventType> = vec![
-        KeyEventType::ProcessNormally,
-        KeyEventType::ProcessNormally,
+        KeyEventType::SendToAT(
+            KeyEvent::new(false, ModMask::empty(), XKeysym::F.into(), Some('F'), 0)
+                .with_matched_keystroke(0),
+        ),
         KeyEventType::SendToAT(KeyEvent::new(
-            false,
+            true,
             ModMask::empty(),
-            XKeysym::H.into(),
-            Some('H'),
+            XKeysym::F.into(),
+            None,
             0,
         )),
+        KeyEventType::SendToAT(
+            KeyEvent::new(false, ModMask::empty(), XKeysym::H.into(), Some('H'), 0)
+                .with_grab_transition(GrabTransition::Started),
+        ),
         KeyEventType::SendToAT(KeyEvent::new(
             false,
             ModMask::empty(),
@@ -104,25 +141,2989 @@ fn test_global_non_standard_keybind() {
             true,
             ModMask::empty(),
             XKeysym::F.into(),
-            Some('F'),
+            None,
             0,
         )),
+        KeyEventType::SendToAT(
+            KeyEvent::new(true, ModMask::empty(), XKeysym::H.into(), None, 0)
+                .with_grab_transition(GrabTransition::Ended),
+        ),
+        KeyEventType::SendToAT(
+            KeyEvent::new(false, ModMask::empty(), XKeysym::F.into(), Some('F'), 0)
+                .with_matched_keystroke(0),
+        ),
         KeyEventType::SendToAT(KeyEvent::new(
             true,
             ModMask::empty(),
-            XKeysym::H.into(),
-            Some('H'),
+            XKeysym::F.into(),
+            None,
             0,
         )),
-        KeyEventType::ProcessNormally,
-        KeyEventType::ProcessNormally,
     ];
     let mut results = Vec::new();
     for ev in events {
         results.push(state.process(Keysym(ev.0), ev.1));
     }
+    assert_logical(&results, &expected_results);
+}
+
+#[test]
+fn test_keysym_modmask_hash() {
+    use std::collections::HashSet;
+    let keys: HashSet<Keysym> = [
+        Keysym(XKeysym::H),
+        Keysym(XKeysym::Caps_Lock),
+        Keysym(XKeysym::new(XKeysym::H.raw())),
+        Keysym(XKeysym::F),
+    ]
+    .into_iter()
+    .collect();
     assert_eq!(
-        expected_results, results,
-        "Expected results do not match the running of the state machine!"
+        keys.len(),
+        3,
+        "Keysyms with the same raw value must deduplicate"
+    );
+    assert!(keys.contains(&Keysym(XKeysym::H)));
+    assert!(keys.contains(&Keysym(XKeysym::F)));
+    assert!(!keys.contains(&Keysym(XKeysym::Insert)));
+
+    let masks: HashSet<ModMask> = [
+        ModMask::empty(),
+        ModMask::default(),
+        ModMask::empty() | Keysym(XKeysym::Caps_Lock),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(masks.len(), 2);
+    assert!(masks.contains(&(ModMask::empty() | Keysym(XKeysym::Caps_Lock))));
+}
+
+#[test]
+fn test_keysym_display() {
+    assert_eq!(Keysym(XKeysym::Caps_Lock).to_string(), "Caps_Lock");
+    assert_eq!(Keysym(XKeysym::H).to_string(), "H");
+    assert_eq!(Keysym(XKeysym::new(0x0100_263a)).to_string(), "U+263A");
+    assert_eq!(Keysym(XKeysym::new(0x1234_5678)).to_string(), "0x12345678");
+}
+
+#[test]
+fn test_keysym_from_str() {
+    use crate::ParseKeysymError;
+    assert_eq!("Caps_Lock".parse(), Ok(Keysym(XKeysym::Caps_Lock)));
+    assert_eq!("F".parse(), Ok(Keysym(XKeysym::F)));
+    assert_eq!("f".parse(), Ok(Keysym(XKeysym::f)));
+    assert_eq!("0xffe5".parse(), Ok(Keysym(XKeysym::Caps_Lock)));
+    assert_eq!(
+        "caps_lock".parse::<Keysym>(),
+        Err(ParseKeysymError::UnknownName("caps_lock".to_string()))
+    );
+    assert_eq!(
+        "0xzz".parse::<Keysym>(),
+        Err(ParseKeysymError::InvalidHex("0xzz".to_string()))
+    );
+    assert_eq!("".parse::<Keysym>(), Err(ParseKeysymError::Empty));
+
+    for key in [
+        Keysym(XKeysym::Caps_Lock),
+        Keysym(XKeysym::H),
+        Keysym(XKeysym::new(0x0100_263a)),
+        Keysym(XKeysym::new(0x1234_5678)),
+    ] {
+        assert_eq!(key.to_string().parse(), Ok(key));
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_keysym_name_cache() {
+    // every named keysym parses back from its name, in all threads alike
+    let parse_all = || {
+        let mut parsed = 0;
+        for raw in 0..=0xffff {
+            let ks = Keysym(XKeysym::new(raw));
+            let Some(name) = ks.0.name() else { continue };
+            let name = name.strip_prefix("XK_").unwrap_or(name);
+            // single characters parse as the character's keysym instead
+            if name.chars().count() == 1 {
+                continue;
+            }
+            assert_eq!(name.parse(), Ok(ks), "{name}");
+            parsed += 1;
+        }
+        parsed
+    };
+    let threads: Vec<_> = (0..4).map(|_| std::thread::spawn(parse_all)).collect();
+    let counts: Vec<usize> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+    assert!(counts[0] > 1000);
+    assert!(counts.iter().all(|c| *c == counts[0]));
+    // the table is built once and shared
+    assert!(core::ptr::eq(crate::keysym_names(), crate::keysym_names()));
+    assert_eq!(
+        crate::keysym_names().get("Caps_Lock"),
+        Some(&XKeysym::Caps_Lock)
+    );
+}
+
+#[test]
+fn test_keysym_try_from_u32() {
+    use crate::InvalidKeysym;
+    // Unicode
+    assert_eq!(
+        Keysym::try_from(0x0101_f980),
+        Ok(Keysym(XKeysym::new(0x0101_f980)))
+    );
+    // legacy
+    assert_eq!(Keysym::try_from(0x61), Ok(Keysym(XKeysym::a)));
+    assert_eq!(Keysym::try_from(0xffe5), Ok(Keysym(XKeysym::Caps_Lock)));
+    // named, outside both
+    assert_eq!(
+        Keysym::try_from(0x1008_ff12),
+        Ok(Keysym(XKeysym::XF86_AudioMute))
+    );
+    assert_eq!(
+        Keysym::try_from(0x00ff_ffff),
+        Ok(Keysym(XKeysym::VoidSymbol))
+    );
+    // invalid
+    assert_eq!(
+        Keysym::try_from(0x1234_5678),
+        Err(InvalidKeysym(0x1234_5678))
+    );
+    assert_eq!(
+        Keysym::try_from(0x0111_0000),
+        Err(InvalidKeysym(0x0111_0000))
+    );
+    // surrogates are not Unicode scalar values
+    assert_eq!(
+        Keysym::try_from(0x0100_d800),
+        Err(InvalidKeysym(0x0100_d800))
+    );
+    assert_eq!(
+        InvalidKeysym(0x1234_5678).to_string(),
+        "0x12345678 is not a valid keysym"
+    );
+}
+
+#[test]
+fn test_modmask_constants() {
+    let shift_ctrl = ModMask::from_mods(&[ModMask::SHIFT, ModMask::CONTROL]);
+    assert_eq!(shift_ctrl.0.raw(), 0b101);
+    assert_eq!(shift_ctrl, ModMask::SHIFT | ModMask::CONTROL);
+    assert_eq!(
+        ModMask::from_mods(&[
+            ModMask::SHIFT,
+            ModMask::CAPS_LOCK,
+            ModMask::CONTROL,
+            ModMask::ALT,
+            ModMask::NUM_LOCK,
+            ModMask::SUPER,
+        ])
+        .0
+        .raw(),
+        0b101_1111
+    );
+    assert!(ModMask::from_mods(&[]).is_empty());
+    assert!(!ModMask::from_mods(&[ModMask::ALT]).is_empty());
+}
+
+#[test]
+fn test_modmask_contains_intersects() {
+    let shift_ctrl = ModMask::SHIFT | ModMask::CONTROL;
+    assert!(shift_ctrl.contains(ModMask::SHIFT));
+    assert!(shift_ctrl.contains(shift_ctrl));
+    assert!(!shift_ctrl.contains(ModMask::SHIFT | ModMask::ALT));
+    assert!(shift_ctrl.contains(ModMask::empty()));
+    assert!(ModMask::empty().contains(ModMask::empty()));
+
+    assert!(shift_ctrl.intersects(ModMask::SHIFT | ModMask::ALT));
+    assert!(!shift_ctrl.intersects(ModMask::ALT));
+    assert!(!shift_ctrl.intersects(ModMask::empty()));
+    assert!(!ModMask::empty().intersects(ModMask::empty()));
+}
+
+#[test]
+fn test_modmask_xor_toggle() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let mut mask = ModMask::empty();
+    mask ^= caps;
+    assert_eq!(mask, ModMask::empty() | caps);
+    mask ^= caps;
+    assert!(mask.is_empty());
+
+    mask = ModMask::SHIFT;
+
+    mask ^= ModMask::CONTROL;
+    assert!(mask.contains(ModMask::CONTROL));
+    mask ^= ModMask::CONTROL;
+    assert!(!mask.contains(ModMask::CONTROL));
+    assert_eq!(mask ^ ModMask::SHIFT, ModMask::empty());
+    assert_eq!((ModMask::empty() ^ caps) ^ caps, ModMask::empty());
+}
+
+#[test]
+fn test_modmask_iter_mods() {
+    assert_eq!(ModMask::empty().iter_mods().count(), 0);
+    assert_eq!(ModMask::empty().count(), 0);
+
+    assert_eq!(
+        ModMask::ALT.iter_mods().collect::<Vec<_>>(),
+        vec![ModMask::ALT]
+    );
+    assert_eq!(ModMask::ALT.count(), 1);
+
+    let mask = ModMask::from_mods(&[ModMask::SUPER, ModMask::SHIFT, ModMask::CONTROL]);
+    assert_eq!(
+        mask.iter_mods().collect::<Vec<_>>(),
+        vec![ModMask::SHIFT, ModMask::CONTROL, ModMask::SUPER]
+    );
+    assert_eq!(mask.count(), 3);
+}
+
+#[test]
+fn test_num_lock_keypad() {
+    let num_lock = Keysym(XKeysym::Num_Lock);
+    let kp_1 = Keysym(XKeysym::KP_1);
+    let kp_end = Keysym(XKeysym::KP_End);
+    let mut state = State {
+        has_client: true,
+        notify_all: true,
+        ..State::default()
+    };
+    let emitted = |d: KeyEventType| {
+        let ev = d.at_event().unwrap();
+        (ev.keysym(), ev.unichar())
+    };
+
+    // off: navigation form
+    assert_eq!(emitted(state.press(kp_1)), (kp_end, None));
+    assert_eq!(emitted(state.release(kp_1)), (kp_end, None));
+    assert_eq!(emitted(state.press(kp_end)), (kp_end, None));
+    let _ = state.release(kp_end);
+
+    // on: numeric form
+    let _ = state.press(num_lock);
+    let _ = state.release(num_lock);
+    assert!(state.locked.contains(ModMask::NUM_LOCK));
+    assert_eq!(emitted(state.press(kp_end)), (kp_1, Some('1')));
+    assert_eq!(emitted(state.release(kp_end)), (kp_1, None));
+    assert_eq!(emitted(state.press(kp_1)), (kp_1, Some('1')));
+    let _ = state.release(kp_1);
+
+    // keys without a second form are left alone
+    let kp_add = Keysym(XKeysym::KP_Add);
+    assert_eq!(emitted(state.press(kp_add)), (kp_add, Some('+')));
+
+    // grabs match the processed keysym, and flushed releases are resolved too
+    let mut state = State {
+        has_client: true,
+        locked: ModMask::NUM_LOCK,
+        ..State::default()
+    };
+    state.add_keystroke(Keystroke::new(kp_end, ModMask::empty()));
+    assert_eq!(state.press(kp_1), KeyEventType::ProcessNormally);
+    assert_eq!(state.release(kp_1), KeyEventType::ProcessNormally);
+    assert_eq!(emitted(state.press(kp_end)), (kp_1, Some('1')));
+    assert_eq!(emitted(state.release(kp_end)), (kp_1, None));
+    assert_eq!(state.release(kp_end), KeyEventType::ProcessNormally);
+    assert_eq!(emitted(state.press(kp_end)), (kp_1, Some('1')));
+    let flushed = state.on_ungrab();
+    assert_eq!(emitted(flushed[0].clone()), (kp_1, None));
+    assert_eq!(state.release(kp_end), KeyEventType::Swallow);
+}
+
+#[test]
+fn test_grab_with_timeout() {
+    use core::time::Duration;
+    let h = Keysym(XKeysym::h);
+    let j = Keysym(XKeysym::j);
+    let k = Keysym(XKeysym::k);
+    let ms = Duration::from_millis;
+    let at = |d: KeyEventType| matches!(d, KeyEventType::SendToAT(_));
+
+    // the grab ends with the first event past the deadline
+    let mut state = State::default();
+    state.grab_with_timeout(ms(100));
+    assert!(at(state.process_at(h, false, ms(10))));
+    assert!(at(state.process_at(h, true, ms(20))));
+    assert!(at(state.process_at(j, false, ms(50))));
+    assert_eq!(
+        state.process_at(k, false, ms(150)),
+        KeyEventType::ProcessNormally
+    );
+    assert!(!state.explicit_grab);
+    assert_eq!(state.grab_deadline, None);
+    // the key held from the grab has been flushed: its repeats and release are swallowed
+    assert_eq!(state.flushed, [j]);
+    assert!(state.pressed.is_empty());
+    assert_eq!(state.process_at(j, false, ms(155)), KeyEventType::Swallow);
+    assert_eq!(state.process_at(j, true, ms(160)), KeyEventType::Swallow);
+    assert!(state.flushed.is_empty());
+    assert_eq!(
+        state.process_at(k, true, ms(170)),
+        KeyEventType::ProcessNormally
+    );
+
+    // driven by a timer, held keys are flushed
+    let mut state = State::default();
+    state.grab_with_timeout(ms(100));
+    assert!(at(state.process_at(j, false, ms(10))));
+    assert!(state.expire_grab(ms(50)).is_empty());
+    assert!(state.explicit_grab);
+    let flushed = state.expire_grab(ms(100));
+    assert_eq!(flushed.len(), 1);
+    assert!(
+        flushed[0]
+            .at_event()
+            .is_some_and(|ev| ev.release() && ev.keysym() == j)
+    );
+    assert!(!state.explicit_grab);
+    assert_eq!(state.process_at(j, true, ms(120)), KeyEventType::Swallow);
+    assert!(state.expire_grab(ms(200)).is_empty());
+
+    // the event ending the grab may be the release of a key held from it
+    let mut state = State::default();
+    state.grab_with_timeout(ms(100));
+    assert!(at(state.process_at(j, false, ms(10))));
+    assert_eq!(state.process_at(j, true, ms(110)), KeyEventType::Swallow);
+    assert!(!state.explicit_grab);
+    assert!(state.flushed.is_empty());
+
+    // an ungrab arriving first cancels the deadline, and a new untimed grab outlives it
+    let mut state = State::default();
+    state.grab_with_timeout(ms(100));
+    assert!(state.on_ungrab().is_empty());
+    assert_eq!(state.grab_deadline, None);
+    state.on_grab();
+    assert!(at(state.process_at(h, false, ms(150))));
+    assert!(state.explicit_grab);
+    // as does GrabKeyboard replacing a running timed grab
+    state.grab_with_timeout(ms(200));
+    state.on_grab();
+    assert!(at(state.process_at(h, true, ms(250))));
+    assert!(state.expire_grab(ms(300)).is_empty());
+    assert!(state.explicit_grab);
+}
+
+#[test]
+fn test_repeat_delay() {
+    use core::time::Duration;
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let h = Keysym(XKeysym::H);
+    let ms = Duration::from_millis;
+    let h_press = KeyEvent::new(false, ModMask::empty(), h, Some('H'), 0);
+    // the events carry their timestamps, which do not matter here
+    let is_h_press =
+        |d: KeyEventType| matches!(&d, KeyEventType::SendToAT(ev) if ev.matches_logical(&h_press));
+
+    // without a delay, every repeated press is forwarded
+    let mut state = State {
+        modifiers: vec![caps],
+        has_client: true,
+        ..State::default()
+    };
+    let _ = state.process_at(caps, false, ms(0));
+    assert!(is_h_press(state.process_at(h, false, ms(10))));
+    assert!(is_h_press(state.process_at(h, false, ms(40))));
+    assert!(is_h_press(state.process_at(h, false, ms(70))));
+
+    // with a delay, repeats inside the window are swallowed
+    let mut state = State {
+        modifiers: vec![caps],
+        has_client: true,
+        repeat_delay: Some(ms(500)),
+        ..State::default()
+    };
+    let _ = state.process_at(caps, false, ms(0));
+    assert!(is_h_press(state.process_at(h, false, ms(10))));
+    assert_eq!(state.process_at(h, false, ms(40)), KeyEventType::Swallow);
+    assert_eq!(state.process_at(h, false, ms(70)), KeyEventType::Swallow);
+    assert!(is_h_press(state.process_at(h, false, ms(600))));
+    assert!(matches!(
+        state.process_at(h, true, ms(610)),
+        KeyEventType::SendToAT(_)
+    ));
+    // a fresh press after release is never a repeat
+    assert!(is_h_press(state.process_at(h, false, ms(620))));
+}
+
+#[test]
+fn test_keycode_passthrough() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let h = Keysym(XKeysym::H);
+    let mut state = State {
+        modifiers: vec![caps],
+        has_client: true,
+        ..State::default()
+    };
+    // evdev KEY_CAPSLOCK and KEY_H
+    let _ = state.process_with_keycode(caps, false, 58);
+    assert_eq!(
+        state.process_with_keycode(h, false, 35),
+        KeyEventType::SendToAT(KeyEvent::new(false, ModMask::empty(), h, Some('H'), 35))
+    );
+    assert_eq!(
+        state.process(h, true),
+        KeyEventType::SendToAT(KeyEvent::new(true, ModMask::empty(), h, None, 0))
+    );
+}
+
+#[test]
+fn test_local_keystroke_requires_modifiers() {
+    let ctrl = Keysym(XKeysym::Control_L);
+    let f = Keysym(XKeysym::F);
+    let ctrl_mask = ModMask::empty() | ctrl;
+    let mut state = State {
+        has_client: true,
+        keystrokes: vec![Keystroke {
+            modifiers: ctrl_mask,
+            keysym: f,
+            trigger: Trigger::OnBoth,
+            required_locks: ModMask::empty(),
+        }]
+        .into(),
+        ..State::default()
+    };
+    let events = [
+        (f, false),
+        (f, true),
+        (ctrl, false),
+        (f, false),
+        (f, true),
+        (ctrl, true),
+        (f, false),
+        (f, true),
+    ];
+    let expected_results = vec![
+        KeyEventType::ProcessNormally,
+        KeyEventType::ProcessNormally,
+        KeyEventType::ProcessNormally,
+        KeyEventType::SendToAT(
+            KeyEvent::new(false, ModMask::CONTROL, f, Some('F'), 0).with_matched_keystroke(0),
+        ),
+        KeyEventType::SendToAT(KeyEvent::new(true, ModMask::CONTROL, f, None, 0)),
+        KeyEventType::ProcessNormally,
+        KeyEventType::ProcessNormally,
+        KeyEventType::ProcessNormally,
+    ];
+    let results: Vec<KeyEventType> = events
+        .into_iter()
+        .map(|(key, release)| state.process(key, release))
+        .collect();
+    assert_eq!(expected_results, results);
+}
+
+#[test]
+fn test_add_remove_keystrokes() {
+    let mut state = State::default();
+    let ctrl_f = Keystroke {
+        modifiers: ModMask::empty() | Keysym(XKeysym::Control_L),
+        keysym: XKeysym::F.into(),
+        trigger: Trigger::OnBoth,
+        required_locks: ModMask::empty(),
+    };
+    let f = Keystroke {
+        modifiers: ModMask::empty(),
+        keysym: XKeysym::F.into(),
+        trigger: Trigger::OnBoth,
+        required_locks: ModMask::empty(),
+    };
+    assert!(state.add_keystroke(ctrl_f.clone()));
+    assert!(!state.add_keystroke(ctrl_f.clone()));
+    assert!(state.add_keystroke(f.clone()));
+    assert_eq!(state.keystrokes, vec![ctrl_f.clone(), f.clone()]);
+
+    assert!(state.remove_keystroke(&ctrl_f));
+    assert!(!state.remove_keystroke(&ctrl_f));
+    assert_eq!(state.keystrokes, vec![f]);
+
+    state.clear_keystrokes();
+    assert!(state.keystrokes.is_empty());
+}
+
+#[test]
+fn test_keystroke_constructors() {
+    let ctrl = Keysym(XKeysym::Control_L);
+    let f = Keysym(XKeysym::F);
+    assert_eq!(
+        Keystroke::new(f, ModMask::empty() | ctrl),
+        Keystroke {
+            modifiers: ModMask::empty() | ctrl,
+            keysym: f,
+            trigger: Trigger::OnBoth,
+            required_locks: ModMask::empty(),
+        }
+    );
+    assert_eq!(
+        Keystroke::new(f, ModMask::empty())
+            .with_modifier(ModMask::SHIFT)
+            .with_modifier(ModMask::CONTROL),
+        Keystroke {
+            modifiers: ModMask::SHIFT | ModMask::CONTROL,
+            keysym: f,
+            trigger: Trigger::OnBoth,
+            required_locks: ModMask::empty(),
+        }
+    );
+}
+
+#[test]
+fn test_reset_runtime_keeps_configuration() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let h = Keysym(XKeysym::H);
+    let keystroke = Keystroke::new(Keysym(XKeysym::F), ModMask::empty());
+    let mut state = State {
+        has_client: true,
+        modifiers: vec![caps],
+        keystrokes: vec![keystroke.clone()].into(),
+        ..State::default()
+    };
+    let _ = state.process(caps, false);
+    let _ = state.process(h, false);
+    assert!(state.grab_all);
+    assert_eq!(state.pressed, vec![h]);
+
+    state.reset_runtime();
+    assert!(!state.grab_all);
+    assert!(state.pressed.is_empty());
+    assert!(state.pressed_modifiers.is_empty());
+    assert!(state.has_client);
+    assert_eq!(state.modifiers, vec![caps]);
+    assert_eq!(state.keystrokes, vec![keystroke]);
+
+    state.reset_all();
+    assert!(!state.has_client);
+    assert!(state.modifiers.is_empty());
+    assert!(state.keystrokes.is_empty());
+}
+
+#[test]
+fn test_global_modifier_in_state() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let h = Keysym(XKeysym::H);
+    let mut state = State {
+        has_client: true,
+        modifiers: vec![caps],
+        ..State::default()
+    };
+    assert_eq!(
+        state.process(caps, false),
+        KeyEventType::SendToAT(
+            KeyEvent::new(false, ModMask::empty(), caps, None, 0)
+                .with_grab_transition(GrabTransition::Started)
+        )
+    );
+    assert_eq!(state.pressed_modifiers, ModMask::empty() | caps);
+    assert_eq!(
+        state.process(h, false),
+        KeyEventType::SendToAT(KeyEvent::new(false, ModMask::empty(), h, Some('H'), 0))
+    );
+    let _ = state.process(h, true);
+    let _ = state.process(caps, true);
+    assert!(state.pressed_modifiers.is_empty());
+}
+
+#[test]
+fn test_stacked_global_modifiers() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let insert = Keysym(XKeysym::Insert);
+    let h = Keysym(XKeysym::H);
+    let mut state = State {
+        has_client: true,
+        modifiers: vec![caps, insert],
+        ..State::default()
+    };
+    let _ = state.process(caps, false);
+    let _ = state.process(insert, false);
+    assert_eq!(state.pressed_modifiers, ModMask::empty() | caps | insert);
+
+    assert_eq!(
+        state.process(caps, true),
+        KeyEventType::SendToAT(KeyEvent::new(true, ModMask::empty(), caps, None, 0))
+    );
+    assert!(state.grab_all, "Insert is still held");
+    assert_eq!(state.pressed_modifiers, ModMask::empty() | insert);
+    assert_eq!(
+        state.process(h, false),
+        KeyEventType::SendToAT(KeyEvent::new(false, ModMask::empty(), h, Some('H'), 0))
+    );
+    let _ = state.process(h, true);
+
+    let _ = state.process(insert, true);
+    assert!(!state.grab_all);
+    assert!(state.pressed_modifiers.is_empty());
+    assert_eq!(state.process(h, false), KeyEventType::ProcessNormally);
+}
+
+#[test]
+fn test_key_event_type_helpers() {
+    let ev = KeyEvent::new(false, ModMask::empty(), XKeysym::H.into(), Some('H'), 0);
+
+    let swallow = KeyEventType::Swallow;
+    assert_eq!(swallow.at_event(), None);
+    assert!(!swallow.should_process());
+    assert!(swallow.should_swallow());
+
+    let normal = KeyEventType::ProcessNormally;
+    assert_eq!(normal.at_event(), None);
+    assert!(normal.should_process());
+    assert!(!normal.should_swallow());
+
+    let to_at = KeyEventType::SendToAT(ev.clone());
+    assert_eq!(to_at.at_event(), Some(&ev));
+    assert!(!to_at.should_process());
+    assert!(to_at.should_swallow());
+
+    let both = KeyEventType::SendToATAndProcess(ev.clone());
+    assert_eq!(both.at_event(), Some(&ev));
+    assert!(both.should_process());
+    assert!(!both.should_swallow());
+}
+
+#[test]
+fn test_key_event_to_signal_args() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let h = Keysym(XKeysym::H);
+    assert_eq!(
+        KeyEvent::new(false, ModMask::empty(), caps, None, 58).to_signal_args(),
+        (false, ModMask::empty(), caps, '\0', 58)
+    );
+    assert_eq!(
+        KeyEvent::new(true, ModMask::empty() | caps, h, Some('H'), 35).to_signal_args(),
+        (true, ModMask::empty() | caps, h, 'H', 35)
+    );
+}
+
+#[test]
+fn test_keysym_ordering() {
+    let mut keys = vec![
+        Keysym(XKeysym::Caps_Lock),
+        Keysym(XKeysym::h),
+        Keysym(XKeysym::Insert),
+        Keysym(XKeysym::H),
+    ];
+    keys.sort();
+    assert_eq!(
+        keys,
+        vec![
+            Keysym(XKeysym::H),
+            Keysym(XKeysym::h),
+            Keysym(XKeysym::Insert),
+            Keysym(XKeysym::Caps_Lock),
+        ]
+    );
+    assert!(keys.windows(2).all(|w| w[0].0.raw() < w[1].0.raw()));
+    assert_eq!(keys.binary_search(&Keysym(XKeysym::Insert)), Ok(2));
+
+    let mut masks = vec![ModMask::SUPER, ModMask::SHIFT, ModMask::CONTROL];
+    masks.sort();
+    assert_eq!(
+        masks,
+        vec![ModMask::SHIFT, ModMask::CONTROL, ModMask::SUPER]
+    );
+}
+
+#[test]
+fn test_keysym_serde_modes() {
+    use crate::NamedKeysym;
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let unnamed = Keysym(XKeysym::new(0x0100_263a));
+
+    // numeric mode, as used on the wire
+    assert_eq!(serde_json::to_string(&caps).unwrap(), "65509");
+    assert_eq!(serde_json::from_str::<Keysym>("65509").unwrap(), caps);
+
+    // named mode
+    assert_eq!(
+        serde_json::to_string(&NamedKeysym(caps)).unwrap(),
+        "\"Caps_Lock\""
+    );
+    assert_eq!(
+        serde_json::to_string(&NamedKeysym(unnamed)).unwrap(),
+        "16787002"
+    );
+    for key in [caps, unnamed, Keysym(XKeysym::H)] {
+        let json = serde_json::to_string(&NamedKeysym(key)).unwrap();
+        assert_eq!(
+            serde_json::from_str::<NamedKeysym>(&json).unwrap(),
+            NamedKeysym(key)
+        );
+    }
+    assert_eq!(
+        serde_json::from_str::<NamedKeysym>("\"0xffe5\"").unwrap(),
+        NamedKeysym(caps)
+    );
+    assert!(serde_json::from_str::<NamedKeysym>("\"Not_A_Key\"").is_err());
+}
+
+#[test]
+fn test_caps_lock_toggle_state() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let f = Keysym(XKeysym::F);
+    let mut state = State {
+        has_client: true,
+        keystrokes: vec![Keystroke::new(f, ModMask::empty())].into(),
+        ..State::default()
+    };
+    assert_eq!(state.process(caps, false), KeyEventType::ProcessNormally);
+    assert_eq!(state.process(caps, true), KeyEventType::ProcessNormally);
+    assert_eq!(state.locked, ModMask::CAPS_LOCK);
+    assert_eq!(
+        state.process(f, false),
+        KeyEventType::SendToAT(
+            KeyEvent::new(false, ModMask::CAPS_LOCK, f, Some('F'), 0).with_matched_keystroke(0)
+        )
+    );
+    let _ = state.process(f, true);
+
+    let _ = state.process(caps, false);
+    let _ = state.process(caps, true);
+    assert!(state.locked.is_empty());
+    assert_eq!(
+        state.process(f, false),
+        KeyEventType::SendToAT(
+            KeyEvent::new(false, ModMask::empty(), f, Some('F'), 0).with_matched_keystroke(0)
+        )
+    );
+
+    // a grabbed Caps_Lock never reaches the compositor, so it does not toggle the lock
+    state.modifiers = vec![caps];
+    let _ = state.process(caps, false);
+    let _ = state.process(caps, true);
+    assert!(state.locked.is_empty());
+}
+
+#[test]
+fn test_lock_state_with_control_held() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let ctrl = Keysym(XKeysym::Control_L);
+    let f = Keysym(XKeysym::f);
+    let mut state = State {
+        has_client: true,
+        keystrokes: vec![Keystroke::new(f, ModMask::empty() | ctrl)].into(),
+        ..State::default()
+    };
+    // the raw Control_L keysym has the Shift and Lock bits set; the event state must not
+    let _ = state.press(ctrl);
+    let event = state.press(f).at_event().unwrap().clone();
+    assert_eq!(event.state(), ModMask::CONTROL);
+    assert!(!event.state().contains(ModMask::CAPS_LOCK));
+    assert!(!event.state().contains(ModMask::SHIFT));
+    let _ = state.release(f);
+    let _ = state.release(ctrl);
+
+    let _ = state.press(caps);
+    let _ = state.release(caps);
+    let _ = state.press(ctrl);
+    let event = state.press(f).at_event().unwrap().clone();
+    assert_eq!(event.state(), ModMask::CONTROL | ModMask::CAPS_LOCK);
+    assert_eq!(event.state().to_string(), "Ctrl+CapsLock");
+}
+
+#[test]
+fn test_held_caps_lock_modifier_is_not_locked() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let h = Keysym(XKeysym::h);
+    let mut state = State {
+        has_client: true,
+        modifiers: vec![caps],
+        ..State::default()
+    };
+    let _ = state.press(caps);
+    let event = state.press(h).at_event().unwrap().clone();
+    assert_eq!(event.state(), ModMask::empty());
+    let _ = state.release(h);
+    let _ = state.release(caps);
+
+    // only an engaged lock sets the bit
+    state.locked = ModMask::CAPS_LOCK;
+    let _ = state.press(caps);
+    let event = state.press(h).at_event().unwrap().clone();
+    assert_eq!(event.state(), ModMask::CAPS_LOCK);
+}
+
+#[test]
+fn test_latched_modifiers() {
+    let ctrl = Keysym(XKeysym::Control_L);
+    let shift = Keysym(XKeysym::Shift_L);
+    let f = Keysym(XKeysym::F);
+    let ctrl_mask = ModMask::empty() | ctrl;
+    let mut state = State {
+        has_client: true,
+        keystrokes: vec![Keystroke::new(f, ctrl_mask)].into(),
+        ..State::default()
+    };
+
+    // latch, then key: the latch applies once
+    state.latch(ctrl_mask);
+    assert_eq!(
+        state.process(f, false),
+        KeyEventType::SendToAT(
+            KeyEvent::new(false, ModMask::CONTROL, f, Some('F'), 0).with_matched_keystroke(0)
+        )
+    );
+    assert!(state.latched.is_empty());
+    let _ = state.process(f, true);
+    assert_eq!(state.process(f, false), KeyEventType::ProcessNormally);
+    let _ = state.process(f, true);
+
+    // latch, then another modifier: the latch stays pending for the next key
+    state.latch(ModMask::SHIFT);
+    assert_eq!(state.process(shift, false), KeyEventType::ProcessNormally);
+    assert_eq!(state.latched, ModMask::SHIFT);
+    let _ = state.process(shift, true);
+    assert_eq!(
+        state.process(Keysym(XKeysym::g), false),
+        KeyEventType::ProcessNormally
+    );
+    assert!(state.latched.is_empty());
+}
+
+#[test]
+fn test_grab_status() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let h = Keysym(XKeysym::h);
+    let mut state = State {
+        has_client: true,
+        modifiers: vec![caps],
+        ..State::default()
+    };
+    let status = state.grab_status();
+    assert!(!status.grab_all);
+    assert!(!status.notify_all);
+    assert!(status.pressed_modifiers.is_empty());
+    assert!(status.pressed.is_empty());
+
+    let _ = state.process(caps, false);
+    let _ = state.process(h, false);
+    let status = state.grab_status();
+    assert!(status.grab_all);
+    assert_eq!(status.pressed_modifiers, ModMask::empty() | caps);
+    assert_eq!(status.pressed, &[h]);
+
+    let _ = state.process(h, true);
+    let _ = state.process(caps, true);
+    let status = state.grab_status();
+    assert!(!status.grab_all);
+    assert!(status.pressed_modifiers.is_empty());
+    assert!(status.pressed.is_empty());
+}
+
+#[test]
+fn test_apply_key_grabs() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let insert = Keysym(XKeysym::Insert);
+    let ctrl_f = Keystroke::new(
+        Keysym(XKeysym::F),
+        ModMask::empty() | Keysym(XKeysym::Control_L),
     );
+    let mut state = State::default();
+
+    // duplicates are dropped
+    state
+        .apply_key_grabs(&[caps, insert, caps], &[ctrl_f.clone(), ctrl_f.clone()])
+        .unwrap();
+    assert_eq!(state.modifiers, vec![caps, insert]);
+    assert_eq!(state.keystrokes, vec![ctrl_f.clone()]);
+
+    // a keystroke on a global modifier is ambiguous, and leaves the state untouched
+    let on_caps = Keystroke::new(caps, ModMask::empty());
+    assert_eq!(
+        state.apply_key_grabs(&[caps], core::slice::from_ref(&on_caps)),
+        Err(SetKeyGrabsError::ModifierKeystroke(on_caps))
+    );
+    assert_eq!(state.modifiers, vec![caps, insert]);
+    assert_eq!(state.keystrokes, vec![ctrl_f]);
+}
+
+#[test]
+fn test_notify_all_lifecycle() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let h = Keysym(XKeysym::h);
+    let mut state = State {
+        modifiers: vec![caps],
+        ..State::default()
+    };
+    state.set_notify_all(true);
+    assert!(state.has_client);
+    assert_eq!(
+        state.process(h, false),
+        KeyEventType::SendToATAndProcess(KeyEvent::new(false, ModMask::empty(), h, Some('h'), 0))
+    );
+    assert_eq!(
+        state.process(h, true),
+        KeyEventType::SendToATAndProcess(KeyEvent::new(true, ModMask::empty(), h, None, 0))
+    );
+
+    // a global grab takes precedence
+    let _ = state.process(caps, false);
+    assert!(matches!(state.process(h, false), KeyEventType::SendToAT(_)));
+    assert!(matches!(state.process(h, true), KeyEventType::SendToAT(_)));
+    let _ = state.process(caps, true);
+
+    state.set_notify_all(false);
+    assert!(!state.notify_all);
+    assert!(state.has_client);
+    assert_eq!(state.process(h, false), KeyEventType::ProcessNormally);
+    assert_eq!(state.process(h, true), KeyEventType::ProcessNormally);
+}
+
+#[test]
+fn test_grab_once() {
+    let ctrl = Keysym(XKeysym::Control_L);
+    let h = Keysym(XKeysym::h);
+    let mut state = State::default();
+    state.grab_once();
+    assert!(state.has_client);
+
+    // modifiers pass through until the key to capture arrives
+    assert_eq!(state.process(ctrl, false), KeyEventType::ProcessNormally);
+    assert_eq!(
+        state.process(h, false),
+        KeyEventType::SendToAT(KeyEvent::new(false, ModMask::empty(), h, Some('h'), 0))
+    );
+    assert!(!state.grab_next_key);
+    assert_eq!(state.process(ctrl, true), KeyEventType::ProcessNormally);
+    assert_eq!(
+        state.process(h, true),
+        KeyEventType::SendToAT(KeyEvent::new(true, ModMask::empty(), h, None, 0))
+    );
+
+    // only a single key is captured
+    assert_eq!(state.process(h, false), KeyEventType::ProcessNormally);
+    assert_eq!(state.process(h, true), KeyEventType::ProcessNormally);
+}
+
+#[test]
+fn test_evdev_keycodes() {
+    use crate::keycode::{evdev_from_keysym, keysym_from_evdev};
+    const KEY_H: u16 = 35;
+    const KEY_1: u16 = 2;
+    const KEY_CAPSLOCK: u16 = 58;
+    assert_eq!(
+        keysym_from_evdev(KEY_H, ModMask::empty()),
+        Some(Keysym(XKeysym::h))
+    );
+    assert_eq!(
+        keysym_from_evdev(KEY_H, ModMask::SHIFT),
+        Some(Keysym(XKeysym::H))
+    );
+    assert_eq!(
+        keysym_from_evdev(KEY_H, ModMask::CAPS_LOCK),
+        Some(Keysym(XKeysym::H))
+    );
+    assert_eq!(
+        keysym_from_evdev(KEY_H, ModMask::SHIFT | ModMask::CAPS_LOCK),
+        Some(Keysym(XKeysym::h))
+    );
+    // caps lock does not affect digits
+    assert_eq!(
+        keysym_from_evdev(KEY_1, ModMask::CAPS_LOCK),
+        Some(Keysym(XKeysym::_1))
+    );
+    assert_eq!(
+        keysym_from_evdev(KEY_1, ModMask::SHIFT),
+        Some(Keysym(XKeysym::exclam))
+    );
+    assert_eq!(
+        keysym_from_evdev(KEY_CAPSLOCK, ModMask::empty()),
+        Some(Keysym(XKeysym::Caps_Lock))
+    );
+    assert_eq!(keysym_from_evdev(0, ModMask::empty()), None);
+
+    assert_eq!(evdev_from_keysym(Keysym(XKeysym::H)), Some(KEY_H));
+    assert_eq!(evdev_from_keysym(Keysym(XKeysym::h)), Some(KEY_H));
+    assert_eq!(evdev_from_keysym(Keysym(XKeysym::exclam)), Some(KEY_1));
+    assert_eq!(
+        evdev_from_keysym(Keysym(XKeysym::Caps_Lock)),
+        Some(KEY_CAPSLOCK)
+    );
+    assert_eq!(evdev_from_keysym(Keysym(XKeysym::eacute)), None);
+}
+
+#[test]
+fn test_evdev_table_round_trip() {
+    use crate::keycode::{US_QWERTY, evdev_from_keysym, keysym_from_evdev};
+    for &(code, plain, shifted) in US_QWERTY {
+        assert_eq!(
+            keysym_from_evdev(code, ModMask::empty()),
+            Some(Keysym(plain))
+        );
+        assert_eq!(
+            keysym_from_evdev(code, ModMask::SHIFT),
+            Some(Keysym(shifted))
+        );
+        assert_eq!(evdev_from_keysym(Keysym(plain)), Some(code), "{plain:?}");
+        assert_eq!(
+            evdev_from_keysym(Keysym(shifted)),
+            Some(code),
+            "{shifted:?}"
+        );
+    }
+    // the whole keypad, KEY_KP7 to KEY_KPDOT
+    for code in 71..=83 {
+        assert!(
+            keysym_from_evdev(code, ModMask::empty()).is_some(),
+            "{code}"
+        );
+    }
+    assert_eq!(
+        keysym_from_evdev(79, ModMask::SHIFT),
+        Some(Keysym(XKeysym::KP_1))
+    );
+}
+
+#[test]
+fn test_grabbed_release_is_swallowed() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let h = Keysym(XKeysym::h);
+    let j = Keysym(XKeysym::j);
+    let mut state = State {
+        has_client: true,
+        modifiers: vec![caps],
+        ..State::default()
+    };
+    let _ = state.process(caps, false);
+    assert!(state.process(h, false).should_swallow());
+    assert!(state.process(j, false).should_swallow());
+    // released within the grab
+    let release = state.process(h, true);
+    assert!(release.should_swallow());
+    assert!(release.at_event().is_some_and(KeyEvent::release));
+    // released after the grab has ended
+    assert!(state.process(caps, true).should_swallow());
+    assert!(!state.grab_all);
+    let release = state.process(j, true);
+    assert!(release.should_swallow());
+    assert!(release.at_event().is_some_and(KeyEvent::release));
+    // nothing is left to swallow
+    assert_eq!(state.process(j, true), KeyEventType::ProcessNormally);
+}
+
+#[test]
+fn test_release_of_key_pressed_before_grab() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let a = Keysym(XKeysym::a);
+    let mut state = State {
+        has_client: true,
+        modifiers: vec![caps],
+        ..State::default()
+    };
+    assert_eq!(state.press(a), KeyEventType::ProcessNormally);
+    let _ = state.press(caps);
+    assert!(!state.is_grabbing(a, true));
+    // the compositor saw the press, so it must see the release too
+    let release = state.release(a);
+    assert!(matches!(
+        &release,
+        KeyEventType::SendToATAndProcess(ev) if ev.release() && ev.keysym() == a
+    ));
+    let _ = state.release(caps);
+
+    // the same for a key pressed before `GrabKeyboard`
+    assert_eq!(state.press(a), KeyEventType::ProcessNormally);
+    state.on_grab();
+    assert!(matches!(
+        state.release(a),
+        KeyEventType::SendToATAndProcess(_)
+    ));
+    // keys pressed within the grab are still hidden
+    assert!(state.press(a).should_swallow());
+    assert!(state.release(a).should_swallow());
+}
+
+#[test]
+fn test_modmask_from_keysyms() {
+    let mods = [
+        Keysym(XKeysym::Control_L),
+        Keysym(XKeysym::Alt_L),
+        Keysym(XKeysym::Control_L),
+    ];
+    let mask: ModMask = mods.iter().copied().collect();
+    assert_eq!(
+        mask,
+        ModMask::empty() | Keysym(XKeysym::Control_L) | Keysym(XKeysym::Alt_L)
+    );
+    assert_eq!(
+        core::iter::empty::<Keysym>().collect::<ModMask>(),
+        ModMask::empty()
+    );
+
+    let mut extended = ModMask::empty() | Keysym(XKeysym::Control_L);
+    extended.extend([Keysym(XKeysym::Alt_L)]);
+    assert_eq!(extended, mask);
+}
+
+#[test]
+fn test_modmask_to_keysyms() {
+    // a single modifier keysym round-trips, up to its left/right variant
+    for ks in [
+        XKeysym::Control_L,
+        XKeysym::Control_R,
+        XKeysym::Super_R,
+        XKeysym::Num_Lock,
+    ] {
+        let mask: ModMask = [Keysym(ks)].into_iter().collect();
+        let back: ModMask = mask.to_keysyms().into_iter().collect();
+        assert_eq!(back.normalize(), mask.normalize(), "{ks:?}");
+    }
+    assert_eq!(
+        (ModMask::empty() | Keysym(XKeysym::Meta_R)).to_keysyms(),
+        [Keysym(XKeysym::Alt_L)]
+    );
+    // standard bits map to their representatives, in bit order
+    let mask = ModMask::from_mods(&[ModMask::SUPER, ModMask::CONTROL, ModMask::SHIFT]);
+    let keysyms = mask.to_keysyms();
+    assert_eq!(
+        keysyms,
+        [
+            Keysym(XKeysym::Shift_L),
+            Keysym(XKeysym::Control_L),
+            Keysym(XKeysym::Super_L)
+        ]
+    );
+    let bits = keysyms
+        .iter()
+        .filter_map(Keysym::canonical_modifier)
+        .fold(ModMask::empty(), |acc, m| acc | m);
+    assert_eq!(bits, mask);
+    // other keys come back as themselves
+    let insert = Keysym(XKeysym::Insert);
+    assert_eq!((ModMask::empty() | insert).to_keysyms(), [insert]);
+    assert!(ModMask::empty().to_keysyms().is_empty());
+}
+
+#[test]
+fn test_grab_observer() {
+    use crate::state_machine::GrabEvent::{
+        ClientConnected, ClientDisconnected, GrabEnded, GrabStarted, KeystrokesChanged,
+    };
+    use core::time::Duration;
+    use std::sync::{Arc, Mutex};
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let x = Keysym(XKeysym::x);
+    let ctrl_h: Keystroke = "Ctrl+h".parse().unwrap();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let take = || core::mem::take(&mut *seen.lock().unwrap());
+    let mut state = State::default();
+    let sink = Arc::clone(&seen);
+    state.set_observer(move |ev| sink.lock().unwrap().push(ev));
+
+    state.on_watch();
+    assert_eq!(take(), [ClientConnected]);
+    state
+        .on_set_key_grabs(&[caps], core::slice::from_ref(&ctrl_h))
+        .unwrap();
+    assert_eq!(take(), [KeystrokesChanged]);
+    // setting the same grabs again changes nothing
+    state
+        .on_set_key_grabs(&[caps], core::slice::from_ref(&ctrl_h))
+        .unwrap();
+    assert_eq!(take(), []);
+
+    let _ = state.press(caps);
+    let _ = state.press(x);
+    let _ = state.release(x);
+    let _ = state.release(caps);
+    assert_eq!(take(), [GrabStarted, GrabEnded]);
+
+    state.on_grab();
+    // a global modifier does not start another grab while the explicit one runs
+    let _ = state.press(caps);
+    let _ = state.release(caps);
+    assert!(state.on_ungrab().is_empty());
+    assert_eq!(take(), [GrabStarted, GrabEnded]);
+
+    assert!(!state.add_keystroke(ctrl_h.clone()));
+    assert!(state.add_keystroke(Keystroke::new(x, ModMask::CONTROL)));
+    assert!(state.remove_keystroke(&ctrl_h));
+    assert_eq!(take(), [KeystrokesChanged, KeystrokesChanged]);
+
+    state.reset_all();
+    assert_eq!(take(), [ClientDisconnected, KeystrokesChanged]);
+
+    // the observer is kept, and also sees grabs ending by themselves
+    state.grab_with_timeout(Duration::from_millis(100));
+    let _ = state.process_at(x, false, Duration::from_millis(200));
+    assert_eq!(take(), [ClientConnected, GrabStarted, GrabEnded]);
+}
+
+/// Compile-time check that the public types can be moved and shared across threads and async
+/// tasks; a new field breaking this fails to build.
+#[test]
+fn test_send_sync() {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    const _: () = {
+        assert_send_sync::<Keysym>();
+        assert_send_sync::<ModMask>();
+        assert_send_sync::<State>();
+        assert_send_sync::<crate::state_machine::StateConfig>();
+        assert_send_sync::<Keystroke>();
+        assert_send_sync::<KeyEvent>();
+        assert_send_sync::<KeyEventType>();
+        assert_send_sync::<crate::record::Trace>();
+        #[cfg(feature = "std")]
+        assert_send_sync::<crate::server::KeyboardMonitorServer>();
+    };
+}
+
+#[test]
+fn test_modifiers_changed_callback() {
+    use std::sync::{Arc, Mutex};
+    let insert = Keysym(XKeysym::Insert);
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let h = Keysym(XKeysym::h);
+    let mut state = State {
+        modifiers: vec![insert, caps],
+        has_client: true,
+        ..State::default()
+    };
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&seen);
+    state.on_modifiers_changed(Box::new(move |m| sink.lock().unwrap().push(m)));
+
+    let _ = state.press(insert);
+    assert_eq!(state.pressed_modifiers(), ModMask::empty() | insert);
+    let _ = state.press(caps);
+    // neither other keys nor repeated modifier presses change the mask
+    let _ = state.press(h);
+    let _ = state.release(h);
+    let _ = state.press(caps);
+    let _ = state.release(insert);
+    let _ = state.release(caps);
+    assert_eq!(state.pressed_modifiers(), ModMask::empty());
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec![
+            ModMask::empty() | insert,
+            ModMask::empty() | insert | caps,
+            ModMask::empty() | caps,
+            ModMask::empty(),
+        ]
+    );
+
+    // the callback survives a full reset
+    state.reset_all();
+    state.modifiers = vec![insert];
+    state.has_client = true;
+    let _ = state.press(insert);
+    assert_eq!(seen.lock().unwrap().len(), 5);
+}
+
+#[cfg(feature = "evdev")]
+#[test]
+fn test_from_evdev() {
+    use crate::keycode::{Layout, from_evdev, is_repeat};
+    use evdev::{EventType, InputEvent};
+    const KEY_H: u16 = 35;
+    const SYN_REPORT: u16 = 0;
+    let layout = Layout::us_qwerty();
+    let h = Keysym(XKeysym::h);
+
+    let press = InputEvent::new(EventType::KEY.0, KEY_H, 1);
+    let repeat = InputEvent::new(EventType::KEY.0, KEY_H, 2);
+    let release = InputEvent::new(EventType::KEY.0, KEY_H, 0);
+    assert_eq!(from_evdev(&press, &layout), Some((h, false)));
+    assert_eq!(from_evdev(&repeat, &layout), Some((h, false)));
+    assert_eq!(from_evdev(&release, &layout), Some((h, true)));
+    assert!(is_repeat(&repeat));
+    assert!(!is_repeat(&press));
+
+    let skipping = layout.with_skip_repeats(true);
+    assert_eq!(from_evdev(&repeat, &skipping), None);
+    assert_eq!(from_evdev(&press, &skipping), Some((h, false)));
+
+    let shifted = layout.with_modifiers(ModMask::SHIFT);
+    assert_eq!(
+        from_evdev(&press, &shifted),
+        Some((Keysym(XKeysym::H), false))
+    );
+
+    // non-key events and unknown codes are skipped
+    let syn = InputEvent::new(EventType::SYNCHRONIZATION.0, SYN_REPORT, 0);
+    assert_eq!(from_evdev(&syn, &layout), None);
+    let unknown = InputEvent::new(EventType::KEY.0, 0, 1);
+    assert_eq!(from_evdev(&unknown, &layout), None);
+    assert!(!is_repeat(&InputEvent::new(EventType::RELATIVE.0, 0, 2)));
+
+    // the output drives the state machine directly
+    let mut state = State::default();
+    state
+        .on_set_key_grabs(&[], &[Keystroke::new(h, ModMask::empty())])
+        .unwrap();
+    let (key, released) = from_evdev(&press, &layout).unwrap();
+    assert!(matches!(
+        state.process(key, released),
+        KeyEventType::SendToAT(_)
+    ));
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+#[tracing_test::traced_test]
+fn test_process_traces_decision() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let mut state = State {
+        has_client: true,
+        modifiers: vec![caps],
+        ..State::default()
+    };
+    let _ = state.process(caps, false);
+    let _ = state.process(Keysym(XKeysym::h), false);
+    assert!(logs_contain("is_mod_global=true"));
+    assert!(logs_contain("key=h"));
+    assert!(logs_contain("decision=SendToAT"));
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+#[tracing_test::traced_test]
+fn test_key_grabs_without_client_warn() {
+    let f = Keystroke::new(Keysym(XKeysym::f), ModMask::empty());
+    let mut state = State::default();
+    state
+        .apply_key_grabs(&[], core::slice::from_ref(&f))
+        .unwrap();
+    assert!(logs_contain("key grabs set without an active client"));
+    // the grabs are kept, but have no effect yet
+    assert_eq!(state.keystrokes, vec![f.clone()]);
+    assert_eq!(state.press(f.keysym), KeyEventType::ProcessNormally);
+    let _ = state.release(f.keysym);
+    state.on_watch();
+    assert!(state.press(f.keysym).should_swallow());
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+#[tracing_test::traced_test]
+fn test_key_grabs_with_client_do_not_warn() {
+    let f = Keystroke::new(Keysym(XKeysym::f), ModMask::empty());
+    let mut state = State::default();
+    state.on_watch();
+    state.on_set_key_grabs(&[], &[f]).unwrap();
+    state.apply_key_grabs(&[], &[]).unwrap();
+    assert!(!logs_contain("without an active client"));
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+#[tracing_test::traced_test]
+fn test_set_key_grabs_before_watch_warns() {
+    let f = Keystroke::new(Keysym(XKeysym::f), ModMask::empty());
+    let mut state = State::default();
+    state
+        .on_set_key_grabs(&[], core::slice::from_ref(&f))
+        .unwrap();
+    assert!(logs_contain(
+        "key grabs set without an active client; SetKeyGrabs should follow WatchKeyboard"
+    ));
+    // the call still marks the client as present
+    assert!(state.has_client);
+    assert!(state.press(f.keysym).should_swallow());
+}
+
+#[test]
+fn test_process_all() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let h = Keysym(XKeysym::H);
+    let mut state = State {
+        has_client: true,
+        modifiers: vec![caps],
+        ..State::default()
+    };
+    let held = ModMask::empty();
+    let results = state.process_all(&[
+        (h, false),
+        (h, true),
+        (caps, false),
+        (h, false),
+        (h, true),
+        (caps, true),
+        (h, false),
+        (h, true),
+    ]);
+    assert_eq!(
+        results,
+        vec![
+            KeyEventType::ProcessNormally,
+            KeyEventType::ProcessNormally,
+            KeyEventType::SendToAT(
+                KeyEvent::new(false, ModMask::empty(), caps, None, 0)
+                    .with_grab_transition(GrabTransition::Started)
+            ),
+            KeyEventType::SendToAT(KeyEvent::new(false, held, h, Some('H'), 0)),
+            KeyEventType::SendToAT(KeyEvent::new(true, held, h, None, 0)),
+            KeyEventType::SendToAT(
+                KeyEvent::new(true, held, caps, None, 0)
+                    .with_grab_transition(GrabTransition::Ended)
+            ),
+            KeyEventType::ProcessNormally,
+            KeyEventType::ProcessNormally,
+        ]
+    );
+    assert!(state.process_all(&[]).is_empty());
+}
+
+#[test]
+fn test_track_without_client() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let h = Keysym(XKeysym::h);
+    let mut state = State {
+        modifiers: vec![caps],
+        track_without_client: true,
+        ..State::default()
+    };
+    assert_eq!(state.process(caps, false), KeyEventType::ProcessNormally);
+    assert_eq!(state.process(h, false), KeyEventType::ProcessNormally);
+    assert_eq!(state.process(h, true), KeyEventType::ProcessNormally);
+    assert_eq!(state.pressed_modifiers, ModMask::empty() | caps);
+    assert!(state.pressed.is_empty());
+
+    // the client connects mid-hold; the grab is already active
+    // (the press reached the compositor, so Caps Lock is locked as well)
+    state.has_client = true;
+    let held = ModMask::CAPS_LOCK;
+    assert_eq!(
+        state.process(h, false),
+        KeyEventType::SendToAT(KeyEvent::new(false, held, h, Some('H'), 0))
+    );
+    let _ = state.process(h, true);
+    // the compositor saw the press of Caps_Lock, so it must see its release too
+    assert!(!state.is_grabbing(caps, true));
+    assert_eq!(
+        state.process(caps, true),
+        KeyEventType::SendToATAndProcess(
+            KeyEvent::new(true, held, caps, None, 0).with_grab_transition(GrabTransition::Ended)
+        )
+    );
+    assert!(state.compositor_modifiers.is_empty());
+    // a later press is grabbed as usual, and so is its release
+    assert!(state.process(caps, false).should_swallow());
+    assert!(state.process(caps, true).should_swallow());
+
+    // by default nothing is tracked without a client
+    let mut state = State {
+        modifiers: vec![caps],
+        ..State::default()
+    };
+    let _ = state.process(caps, false);
+    state.has_client = true;
+    assert_eq!(state.process(h, false), KeyEventType::ProcessNormally);
+}
+
+#[test]
+fn test_keysym_eq_inner_and_raw() {
+    assert_eq!(Keysym(XKeysym::H), XKeysym::H);
+    assert_eq!(XKeysym::H, Keysym(XKeysym::H));
+    assert_ne!(Keysym(XKeysym::H), XKeysym::h);
+    assert_eq!(Keysym(XKeysym::h), 0x0068u32);
+    assert_eq!(0x0048u32, Keysym(XKeysym::H));
+    assert_ne!(Keysym(XKeysym::H), 0x0068u32);
+}
+
+#[test]
+fn test_grab_transitions() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let insert = Keysym(XKeysym::Insert);
+    let h = Keysym(XKeysym::h);
+    let mut state = State {
+        has_client: true,
+        modifiers: vec![caps, insert],
+        ..State::default()
+    };
+    let transition = |d: KeyEventType| d.at_event().and_then(KeyEvent::grab_transition);
+    assert_eq!(
+        transition(state.process(caps, false)),
+        Some(GrabTransition::Started)
+    );
+    assert_eq!(transition(state.process(insert, false)), None);
+    assert_eq!(transition(state.process(h, false)), None);
+    assert_eq!(transition(state.process(h, true)), None);
+    assert_eq!(transition(state.process(caps, true)), None);
+    assert_eq!(
+        transition(state.process(insert, true)),
+        Some(GrabTransition::Ended)
+    );
+    assert_eq!(state.process(h, false), KeyEventType::ProcessNormally);
+}
+
+#[test]
+fn test_keysym_default() {
+    assert_eq!(Keysym::default(), 0u32);
+    assert_eq!(Keysym::default(), XKeysym::NoSymbol);
+}
+
+#[test]
+fn test_keysym_is_modifier() {
+    for k in [
+        XKeysym::Shift_L,
+        XKeysym::Shift_R,
+        XKeysym::Control_L,
+        XKeysym::Control_R,
+        XKeysym::Alt_L,
+        XKeysym::Alt_R,
+        XKeysym::Super_L,
+        XKeysym::Super_R,
+        XKeysym::Caps_Lock,
+        XKeysym::Num_Lock,
+        XKeysym::ISO_Level3_Shift,
+    ] {
+        assert!(Keysym(k).is_modifier(), "{k:?}");
+    }
+    for k in [
+        XKeysym::a,
+        XKeysym::H,
+        XKeysym::Insert,
+        XKeysym::Escape,
+        XKeysym::F1,
+        XKeysym::space,
+        XKeysym::NoSymbol,
+    ] {
+        assert!(!Keysym(k).is_modifier(), "{k:?}");
+    }
+}
+
+#[test]
+fn test_keystroke_tuple_conversion() {
+    let ctrl = ModMask::empty() | Keysym(XKeysym::Control_L);
+    let wire = (Keysym(XKeysym::F), ctrl);
+    let ks = Keystroke::from(wire);
+    assert_eq!(ks.keysym, Keysym(XKeysym::F));
+    assert_eq!(ks.modifiers, ctrl);
+    assert_eq!(ks, Keystroke::new(Keysym(XKeysym::F), ctrl));
+    assert_eq!(<(Keysym, ModMask)>::from(ks), wire);
+}
+
+#[test]
+fn test_grab_all_notify_all_precedence() {
+    let h = Keysym(XKeysym::h);
+    let event = |release| KeyEvent::new(release, ModMask::empty(), h, (!release).then_some('h'), 0);
+    for (grab_all, notify_all) in [(false, false), (false, true), (true, false), (true, true)] {
+        let mut state = State {
+            has_client: true,
+            grab_all,
+            notify_all,
+            ..State::default()
+        };
+        let expected = |release| match (grab_all, notify_all) {
+            (true, _) => KeyEventType::SendToAT(event(release)),
+            (false, true) => KeyEventType::SendToATAndProcess(event(release)),
+            (false, false) => KeyEventType::ProcessNormally,
+        };
+        assert_eq!(
+            state.process(h, false),
+            expected(false),
+            "{grab_all} {notify_all}"
+        );
+        assert_eq!(
+            state.process(h, true),
+            expected(true),
+            "{grab_all} {notify_all}"
+        );
+    }
+}
+
+#[test]
+fn test_press_release() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let h = Keysym(XKeysym::H);
+    let mut state = State {
+        has_client: true,
+        modifiers: vec![caps],
+        ..State::default()
+    };
+    let held = ModMask::empty();
+    assert_eq!(state.press(h), KeyEventType::ProcessNormally);
+    assert_eq!(state.release(h), KeyEventType::ProcessNormally);
+    assert_eq!(
+        state.press(caps),
+        KeyEventType::SendToAT(
+            KeyEvent::new(false, ModMask::empty(), caps, None, 0)
+                .with_grab_transition(GrabTransition::Started)
+        )
+    );
+    assert_eq!(
+        state.press(h),
+        KeyEventType::SendToAT(KeyEvent::new(false, held, h, Some('H'), 0))
+    );
+    assert_eq!(
+        state.release(h),
+        KeyEventType::SendToAT(KeyEvent::new(true, held, h, None, 0))
+    );
+    assert_eq!(
+        state.release(caps),
+        KeyEventType::SendToAT(
+            KeyEvent::new(true, held, caps, None, 0).with_grab_transition(GrabTransition::Ended)
+        )
+    );
+    assert_eq!(state.press(h), KeyEventType::ProcessNormally);
+    assert_eq!(state.release(h), KeyEventType::ProcessNormally);
+}
+
+#[test]
+fn test_unichar_follows_shift() {
+    let shift = Keysym(XKeysym::Shift_L);
+    let h = Keysym(XKeysym::h);
+    let one = Keysym(XKeysym::_1);
+    let unichar = |d: KeyEventType| d.at_event().and_then(KeyEvent::unichar);
+    let mut state = State {
+        has_client: true,
+        grab_all: true,
+        ..State::default()
+    };
+    assert_eq!(unichar(state.press(h)), Some('h'));
+    // releases type nothing
+    assert_eq!(unichar(state.release(h)), None);
+    assert_eq!(unichar(state.press(one)), Some('1'));
+    let _ = state.release(one);
+    // an already shifted keysym is left alone
+    assert_eq!(unichar(state.press(Keysym(XKeysym::H))), Some('H'));
+    let _ = state.release(Keysym(XKeysym::H));
+
+    // Caps Lock only affects letters
+    state.locked = ModMask::CAPS_LOCK;
+    assert_eq!(unichar(state.press(h)), Some('H'));
+    let _ = state.release(h);
+    assert_eq!(unichar(state.press(one)), Some('1'));
+    let _ = state.release(one);
+    state.locked = ModMask::empty();
+
+    // a latched Shift
+    state.latch(ModMask::empty() | shift);
+    assert_eq!(unichar(state.press(h)), Some('H'));
+    let _ = state.release(h);
+    state.latch(ModMask::SHIFT);
+    assert_eq!(unichar(state.press(h)), Some('H'));
+    let _ = state.release(h);
+    // the raw bits of Control_L include those of Shift_L, but it is no Shift
+    state.latch(ModMask::empty() | Keysym(XKeysym::Control_L));
+    assert_eq!(unichar(state.press(h)), Some('h'));
+    let _ = state.release(h);
+
+    // a held Shift, tracked as the modifier of local keystrokes
+    let mut state = State {
+        has_client: true,
+        keystrokes: vec![
+            Keystroke::new(h, ModMask::empty() | shift),
+            Keystroke::new(one, ModMask::empty() | shift),
+        ]
+        .into(),
+        ..State::default()
+    };
+    let _ = state.press(shift);
+    assert_eq!(unichar(state.press(h)), Some('H'));
+    let _ = state.release(h);
+    assert_eq!(unichar(state.press(one)), Some('!'));
+}
+
+#[test]
+fn test_unconfigured_shift_is_tracked() {
+    let shift = Keysym(XKeysym::Shift_L);
+    let a = Keysym(XKeysym::a);
+    let typed = |d: KeyEventType| d.at_event().map(|ev| (ev.unichar(), ev.state()));
+    let mut state = State {
+        has_client: true,
+        notify_all: true,
+        ..State::default()
+    };
+    assert_eq!(typed(state.press(shift)), Some((None, ModMask::empty())));
+    assert_eq!(typed(state.press(a)), Some((Some('A'), ModMask::SHIFT)));
+    assert_eq!(typed(state.release(a)), Some((None, ModMask::SHIFT)));
+    assert_eq!(typed(state.release(shift)), Some((None, ModMask::SHIFT)));
+    assert_eq!(typed(state.press(a)), Some((Some('a'), ModMask::empty())));
+    let _ = state.release(a);
+
+    // also while no client is connected
+    state.has_client = false;
+    assert_eq!(state.press(shift), KeyEventType::ProcessNormally);
+    state.has_client = true;
+    assert_eq!(typed(state.press(a)), Some((Some('A'), ModMask::SHIFT)));
+    let _ = state.release(a);
+    let _ = state.release(shift);
+    assert!(state.held_shift.is_empty());
+}
+
+#[test]
+fn test_keystroke_patterns() {
+    use crate::state_machine::KeystrokePattern;
+    let insert = Keysym(XKeysym::Insert);
+    let ctrl = Keysym(XKeysym::Control_L);
+    let fkeys = vec![Keysym(XKeysym::F1), Keysym(XKeysym::F2)];
+    let mut state = State {
+        has_client: true,
+        patterns: vec![
+            (KeystrokePattern::Any, ModMask::empty() | insert),
+            (
+                KeystrokePattern::OneOf(fkeys.clone()),
+                ModMask::empty() | ctrl,
+            ),
+            (Keysym(XKeysym::q).into(), ModMask::empty() | ctrl),
+        ],
+        ..State::default()
+    };
+    assert!(KeystrokePattern::Exact(insert).matches(insert));
+    assert!(!KeystrokePattern::Exact(insert).matches(ctrl));
+
+    // any key while Insert is held
+    assert_eq!(state.press(insert), KeyEventType::ProcessNormally);
+    for key in [
+        Keysym(XKeysym::a),
+        Keysym(XKeysym::F1),
+        Keysym(XKeysym::space),
+    ] {
+        assert!(
+            matches!(state.press(key), KeyEventType::SendToAT(_)),
+            "{key}"
+        );
+        assert!(
+            matches!(state.release(key), KeyEventType::SendToAT(_)),
+            "{key}"
+        );
+    }
+    assert_eq!(state.release(insert), KeyEventType::ProcessNormally);
+    assert_eq!(
+        state.press(Keysym(XKeysym::a)),
+        KeyEventType::ProcessNormally
+    );
+    assert_eq!(
+        state.release(Keysym(XKeysym::a)),
+        KeyEventType::ProcessNormally
+    );
+
+    // one of the function keys, or exactly q, while Control is held
+    let _ = state.press(ctrl);
+    for key in &fkeys {
+        assert!(
+            matches!(state.press(*key), KeyEventType::SendToAT(_)),
+            "{key}"
+        );
+        assert!(
+            matches!(state.release(*key), KeyEventType::SendToAT(_)),
+            "{key}"
+        );
+    }
+    assert!(matches!(
+        state.press(Keysym(XKeysym::q)),
+        KeyEventType::SendToAT(_)
+    ));
+    assert!(matches!(
+        state.release(Keysym(XKeysym::q)),
+        KeyEventType::SendToAT(_)
+    ));
+    assert_eq!(
+        state.press(Keysym(XKeysym::F3)),
+        KeyEventType::ProcessNormally
+    );
+    assert_eq!(
+        state.release(Keysym(XKeysym::F3)),
+        KeyEventType::ProcessNormally
+    );
+}
+
+#[test]
+fn test_state_config_round_trip() {
+    use crate::state_machine::{KeystrokePattern, StateConfig};
+    use core::time::Duration;
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let ctrl = ModMask::empty() | Keysym(XKeysym::Control_L);
+    let mut state = State {
+        has_client: true,
+        modifiers: vec![caps],
+        keystrokes: vec![Keystroke::new(Keysym(XKeysym::F), ctrl)].into(),
+        patterns: vec![(KeystrokePattern::OneOf(vec![Keysym(XKeysym::F1)]), ctrl)],
+        repeat_delay: Some(Duration::from_millis(250)),
+        ..State::default()
+    };
+    // runtime state is not part of the configuration
+    let _ = state.press(caps);
+    let _ = state.press(Keysym(XKeysym::h));
+
+    let json = serde_json::to_string(&state.config()).unwrap();
+    let config: StateConfig = serde_json::from_str(&json).unwrap();
+    assert_eq!(config, state.config());
+
+    let restored = State::from(config);
+    assert_eq!(restored.config(), state.config());
+    assert!(!restored.grab_all);
+    assert!(restored.pressed.is_empty());
+    assert!(restored.pressed_modifiers.is_empty());
+}
+
+#[test]
+fn test_matched_keystroke_index() {
+    let ctrl = Keysym(XKeysym::Control_L);
+    let alt = Keysym(XKeysym::Alt_L);
+    let f = Keysym(XKeysym::f);
+    let mut state = State {
+        has_client: true,
+        keystrokes: vec![
+            Keystroke::new(f, ModMask::empty() | ctrl),
+            Keystroke::new(f, ModMask::empty() | alt),
+            Keystroke::new(Keysym(XKeysym::g), ModMask::empty() | alt),
+        ]
+        .into(),
+        ..State::default()
+    };
+    let matched = |d: KeyEventType| d.at_event().and_then(KeyEvent::matched_keystroke);
+    let _ = state.press(alt);
+    let press = state.press(f);
+    assert_eq!(
+        press,
+        KeyEventType::SendToAT(
+            KeyEvent::new(false, ModMask::ALT, f, Some('f'), 0).with_matched_keystroke(1)
+        )
+    );
+    assert_eq!(matched(state.release(f)), None);
+    assert_eq!(matched(state.press(Keysym(XKeysym::g))), Some(2));
+    let _ = state.release(Keysym(XKeysym::g));
+    let _ = state.release(alt);
+    let _ = state.press(ctrl);
+    assert_eq!(matched(state.press(f)), Some(0));
+}
+
+#[test]
+fn test_modmask_difference() {
+    let shift_ctrl = ModMask::SHIFT | ModMask::CONTROL;
+    let ctrl_alt = ModMask::CONTROL | ModMask::ALT;
+    // overlapping
+    assert_eq!(shift_ctrl.difference(ctrl_alt), ModMask::SHIFT);
+    assert_eq!(ctrl_alt.difference(shift_ctrl), ModMask::ALT);
+    assert_eq!(
+        shift_ctrl.symmetric_difference(ctrl_alt),
+        ModMask::SHIFT | ModMask::ALT
+    );
+    assert!(shift_ctrl.difference(shift_ctrl).is_empty());
+    assert!(shift_ctrl.symmetric_difference(shift_ctrl).is_empty());
+    // disjoint
+    assert_eq!(ModMask::SHIFT.difference(ModMask::ALT), ModMask::SHIFT);
+    assert_eq!(
+        ModMask::SHIFT.symmetric_difference(ModMask::ALT),
+        ModMask::SHIFT | ModMask::ALT
+    );
+    assert_eq!(ModMask::SHIFT.difference(ModMask::empty()), ModMask::SHIFT);
+}
+
+#[test]
+fn test_ungrab_swallows_orphaned_modifier_release() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let insert = Keysym(XKeysym::Insert);
+    let h = Keysym(XKeysym::h);
+    let mut state = State {
+        has_client: true,
+        modifiers: vec![caps, insert],
+        ..State::default()
+    };
+    let _ = state.press(caps);
+    let _ = state.press(insert);
+    assert!(state.grab_all);
+    state.ungrab();
+    assert!(!state.grab_all);
+    assert_eq!(state.press(h), KeyEventType::ProcessNormally);
+    assert_eq!(state.release(h), KeyEventType::ProcessNormally);
+    // the grab stays off while the remaining modifier is held
+    assert_eq!(state.release(caps), KeyEventType::Swallow);
+    assert!(!state.grab_all);
+    assert_eq!(state.release(insert), KeyEventType::Swallow);
+    assert!(state.held_modifiers.is_empty());
+    assert!(state.pressed_modifiers.is_empty());
+    // a fresh press grabs again
+    assert!(matches!(state.press(caps), KeyEventType::SendToAT(_)));
+    assert!(state.grab_all);
+}
+
+#[test]
+fn test_keystrokes_lookup_matches_linear_scan() {
+    use crate::state_machine::Keystrokes;
+    let mods = [
+        ModMask::empty(),
+        ModMask::empty() | Keysym(XKeysym::Control_L),
+        ModMask::empty() | Keysym(XKeysym::Alt_L),
+        ModMask::empty() | Keysym(XKeysym::Control_L) | Keysym(XKeysym::Alt_L),
+    ];
+    let list: Vec<Keystroke> = (0x20..0x7f_u32)
+        .flat_map(|raw| {
+            mods.iter()
+                .map(move |m| Keystroke::new(Keysym(XKeysym::new(raw)), *m))
+        })
+        .collect();
+    let mut keystrokes: Keystrokes = list.iter().cloned().collect();
+    // duplicates are dropped
+    for ks in &list {
+        assert!(!keystrokes.insert(ks.clone()));
+    }
+    assert_eq!(keystrokes, list);
+
+    let check = |keystrokes: &Keystrokes| {
+        for raw in 0x10..0x90_u32 {
+            let key = Keysym(XKeysym::new(raw));
+            let linear: Vec<&Keystroke> = keystrokes.iter().filter(|ks| ks.keysym == key).collect();
+            assert_eq!(keystrokes.matching(key).collect::<Vec<_>>(), linear);
+            for m in mods {
+                assert_eq!(
+                    keystrokes.position(key, m),
+                    keystrokes
+                        .iter()
+                        .position(|ks| ks.keysym == key && ks.modifiers == m)
+                );
+            }
+        }
+    };
+    check(&keystrokes);
+    // indices stay correct after removals
+    assert!(keystrokes.remove(&list[5]));
+    assert!(!keystrokes.remove(&list[5]));
+    assert!(keystrokes.remove(&list[100]));
+    check(&keystrokes);
+    assert_eq!(keystrokes.len(), list.len() - 2);
+    assert_eq!(keystrokes.into_iter().count(), list.len() - 2);
+}
+
+#[test]
+fn test_keysym_from_char() {
+    assert_eq!(Keysym::from_char('a'), Some(Keysym(XKeysym::a)));
+    assert_eq!(Keysym::from_char('Z'), Some(Keysym(XKeysym::Z)));
+    assert_eq!(Keysym::from_char('é'), Some(Keysym(XKeysym::eacute)));
+    assert_eq!(Keysym::from_char('😀'), None);
+    for c in [' ', 'a', 'Z', '~', 'é', 'ÿ'] {
+        assert_eq!(Keysym::from_char(c).and_then(|k| k.key_char()), Some(c));
+    }
+}
+
+#[test]
+fn test_key_event_timestamp() {
+    use core::time::Duration;
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let h = Keysym(XKeysym::h);
+    let at = Duration::from_micros(1_234_567);
+    let mut state = State {
+        has_client: true,
+        modifiers: vec![caps],
+        ..State::default()
+    };
+    let _ = state.press(caps);
+    let press = state.process_at(h, false, at);
+    let event = press.at_event().unwrap();
+    assert_eq!(event.timestamp(), Some(at));
+    // the signal arguments are unaffected
+    assert_eq!(event.to_signal_args(), (false, ModMask::empty(), h, 'h', 0));
+    assert_eq!(state.release(h).at_event().unwrap().timestamp(), None);
+}
+
+#[test]
+fn test_record_and_replay() {
+    use crate::record::{StateRecorder, Trace, replay};
+    use crate::state_machine::StateConfig;
+    let mut recorder = StateRecorder::new(StateConfig {
+        has_client: true,
+        modifiers: vec![Keysym(XKeysym::Caps_Lock)],
+        ..StateConfig::default()
+    });
+    for (key, release) in [
+        (XKeysym::H, false),
+        (XKeysym::H, true),
+        (XKeysym::Caps_Lock, false),
+        (XKeysym::H, false),
+        (XKeysym::H, true),
+        (XKeysym::Caps_Lock, true),
+        (XKeysym::H, false),
+        (XKeysym::H, true),
+    ] {
+        let _ = recorder.process(Keysym(key), release);
+    }
+    assert!(recorder.state().grab_status().pressed.is_empty());
+    let trace = recorder.into_trace();
+    assert_eq!(trace.events.len(), 8);
+    assert!(trace.events[3].result.should_swallow());
+
+    let json = serde_json::to_string(&trace).unwrap();
+    let trace: Trace = serde_json::from_str(&json).unwrap();
+    let results = replay(&trace);
+    assert_eq!(trace.first_divergence(&results), None);
+
+    let mut tampered = trace.clone();
+    tampered.events[3].result = KeyEventType::ProcessNormally;
+    assert_eq!(tampered.first_divergence(&results), Some(3));
+    assert_eq!(trace.first_divergence(&results[..5]), Some(5));
+}
+
+#[test]
+fn test_global_modifier_also_pressed() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let h = Keysym(XKeysym::h);
+    // inconsistent state: the global modifier is also a pressed key
+    let mut state = State {
+        has_client: true,
+        modifiers: vec![caps],
+        pressed: vec![caps],
+        ..State::default()
+    };
+    let ev = state.release(caps);
+    assert_eq!(
+        ev,
+        KeyEventType::SendToAT(KeyEvent::new(true, ModMask::empty(), caps, None, 0))
+    );
+    assert!(state.grab_status().pressed.is_empty());
+    assert!(!state.grab_all);
+
+    // a press is a press of the global modifier
+    state.pressed.push(caps);
+    assert!(state.press(caps).should_swallow());
+    assert!(state.grab_all);
+    assert!(state.grab_status().pressed.is_empty());
+    let ev = state.release(caps);
+    assert_eq!(
+        ev.at_event().and_then(KeyEvent::grab_transition),
+        Some(GrabTransition::Ended)
+    );
+    assert!(!state.grab_all);
+
+    // a grabbed key becomes a global modifier while it is held
+    let mut state = State {
+        has_client: true,
+        ..State::default()
+    };
+    state
+        .apply_key_grabs(&[], &[Keystroke::new(h, ModMask::empty())])
+        .unwrap();
+    assert!(state.press(h).should_swallow());
+    state.apply_key_grabs(&[h], &[]).unwrap();
+    assert!(state.release(h).should_swallow());
+    assert!(state.grab_status().pressed.is_empty());
+    assert_eq!(
+        state.press(Keysym(XKeysym::a)),
+        KeyEventType::ProcessNormally
+    );
+}
+
+#[test]
+fn test_state_with_capacity() {
+    let mut state = State::with_capacity(64, 8);
+    assert!(state.keystrokes.capacity() >= 64);
+    assert!(state.pressed.capacity() >= 8);
+    assert!(state.keystrokes.is_empty());
+    assert_eq!(state.config(), State::default().config());
+
+    let mut default = State::default();
+    for state in [&mut state, &mut default] {
+        state.has_client = true;
+        state.modifiers = vec![Keysym(XKeysym::Caps_Lock)];
+    }
+    for (key, release) in [
+        (XKeysym::Caps_Lock, false),
+        (XKeysym::H, false),
+        (XKeysym::H, true),
+        (XKeysym::Caps_Lock, true),
+        (XKeysym::H, false),
+    ] {
+        assert_eq!(
+            state.process(Keysym(key), release),
+            default.process(Keysym(key), release)
+        );
+    }
+}
+
+#[test]
+fn test_xkb_mask_mapping() {
+    use crate::ModMapping;
+    let ctrl_shift = ModMask::from_mods(&[ModMask::CONTROL, ModMask::SHIFT]);
+    assert_eq!(ctrl_shift.to_xkb_mask(&ModMapping::CORE), 0b101);
+    assert_eq!(ModMask::from_xkb_mask(0b101, &ModMapping::CORE), ctrl_shift);
+
+    // Alt and Super as virtual modifiers after the eight core ones, NumLock unmapped
+    let mapping = ModMapping {
+        alt: Some(8),
+        logo: Some(11),
+        num_lock: None,
+        ..ModMapping::CORE
+    };
+    let alt_super = ModMask::from_mods(&[ModMask::ALT, ModMask::SUPER, ModMask::CAPS_LOCK]);
+    let xkb = alt_super.to_xkb_mask(&mapping);
+    assert_eq!(xkb, (1 << 8) | (1 << 11) | (1 << 1));
+    assert_eq!(ModMask::from_xkb_mask(xkb, &mapping), alt_super);
+    // bits without a modifier in the mapping are dropped both ways
+    assert_eq!(
+        ModMask::from_xkb_mask(xkb | (1 << 3) | (1 << 20), &mapping),
+        alt_super
+    );
+    assert_eq!(ModMask::NUM_LOCK.to_xkb_mask(&mapping), 0);
+    assert_eq!(
+        ModMask::from_xkb_mask(u32::MAX, &ModMapping::default()),
+        ModMask::empty()
+    );
+}
+
+#[test]
+fn test_release_has_no_unichar() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let a = Keysym(XKeysym::a);
+    let mut state = State {
+        has_client: true,
+        notify_all: true,
+        modifiers: vec![caps],
+        ..State::default()
+    };
+    let unichar = |d: KeyEventType| d.at_event().map(KeyEvent::unichar);
+    // notify_all
+    assert_eq!(unichar(state.press(a)), Some(Some('a')));
+    assert_eq!(unichar(state.release(a)), Some(None));
+    // global grab
+    let _ = state.press(caps);
+    assert_eq!(unichar(state.press(a)), Some(Some('a')));
+    assert_eq!(unichar(state.release(a)), Some(None));
+    assert_eq!(
+        state.release(caps).at_event().unwrap().to_signal_args().3,
+        '\0'
+    );
+}
+
+#[test]
+fn test_is_grabbing_matches_process() {
+    use crate::state_machine::KeystrokePattern;
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let ctrl = Keysym(XKeysym::Control_L);
+    let h = Keysym(XKeysym::h);
+    let x = Keysym(XKeysym::x);
+    let f1 = Keysym(XKeysym::F1);
+    let mute = Keysym(XKeysym::XF86_AudioMute);
+    let mut state = State {
+        has_client: true,
+        modifiers: vec![caps],
+        keystrokes: vec!["Ctrl+h".parse().unwrap()].into(),
+        patterns: vec![(KeystrokePattern::Exact(f1), ModMask::empty())],
+        passthrough: vec![mute],
+        ..State::default()
+    };
+    let check = |state: &mut State, key: Keysym, release: bool| {
+        let predicted = state.is_grabbing(key, release);
+        assert_eq!(
+            predicted,
+            state.process(key, release).should_swallow(),
+            "{key} {release}"
+        );
+        predicted
+    };
+    let events = [
+        (x, false, false),
+        (x, true, false),
+        (ctrl, false, false),
+        (h, false, true),
+        (ctrl, true, false),
+        (h, true, true),
+        (f1, false, true),
+        (f1, true, true),
+        (caps, false, true),
+        (x, false, true),
+        (mute, false, false),
+        (mute, true, false),
+        (caps, true, true),
+        (x, true, true),
+        (h, false, false),
+        (h, true, false),
+    ];
+    for (key, release, grabbed) in events {
+        assert_eq!(check(&mut state, key, release), grabbed, "{key} {release}");
+    }
+    // the prediction does not change anything
+    assert!(state.is_grabbing(caps, false));
+    assert!(!state.grab_all);
+
+    state.grab_once();
+    assert!(check(&mut state, x, false));
+    assert!(check(&mut state, x, true));
+    state.on_grab();
+    assert!(check(&mut state, x, false));
+    let _ = state.on_ungrab();
+    assert!(check(&mut state, x, true));
+    state.has_client = false;
+    assert!(!check(&mut state, h, false));
+}
+
+#[cfg(feature = "arbitrary")]
+#[test]
+fn test_random_event_sequences() {
+    use arbitrary::{Arbitrary, Unstructured};
+    // xorshift, so every run covers the same sequences
+    let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+    let mut bytes = vec![0u8; 512];
+    for _ in 0..200 {
+        for b in &mut bytes {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            *b = seed.to_le_bytes()[0];
+        }
+        let mut u = Unstructured::new(&bytes);
+        let modifiers: Vec<Keysym> = (0..u.int_in_range(0..=2).unwrap())
+            .map(|_| Keysym::arbitrary(&mut u).unwrap())
+            .collect();
+        let keystrokes: Vec<Keystroke> = (0..u.int_in_range(0..=4).unwrap())
+            .map(|_| Keystroke::arbitrary(&mut u).unwrap())
+            .collect();
+        // draw most events from the configured keys, so they actually interact
+        let mut pool: Vec<Keysym> = modifiers.clone();
+        pool.extend(keystrokes.iter().map(|ks| ks.keysym));
+        pool.extend([Keysym(XKeysym::Shift_L), Keysym(XKeysym::a)]);
+        let mut state = State {
+            has_client: bool::arbitrary(&mut u).unwrap(),
+            notify_all: bool::arbitrary(&mut u).unwrap(),
+            track_without_client: bool::arbitrary(&mut u).unwrap(),
+            modifiers,
+            keystrokes: keystrokes.into(),
+            ..State::default()
+        };
+        while !u.is_empty() {
+            let (key, release) = if u.ratio(1, 4).unwrap() {
+                <(Keysym, bool)>::arbitrary(&mut u).unwrap()
+            } else {
+                (*u.choose(&pool).unwrap(), bool::arbitrary(&mut u).unwrap())
+            };
+            let predicted = state.is_grabbing(key, release);
+            let decision = state.process(key, release);
+            assert_eq!(
+                predicted,
+                decision.should_swallow(),
+                "{key} {release} {state:?}"
+            );
+            let mut pressed = state.pressed.clone();
+            pressed.sort();
+            pressed.dedup();
+            assert_eq!(pressed.len(), state.pressed.len(), "{state:?}");
+            assert!(
+                !state.pressed.iter().any(|k| state.modifiers.contains(k)),
+                "{state:?}"
+            );
+            let global_held = state
+                .held_modifiers
+                .iter()
+                .any(|k| state.modifiers.contains(k));
+            if state.has_client || state.track_without_client {
+                assert_eq!(state.grab_all, global_held, "{state:?}");
+            } else {
+                assert!(!state.grab_all, "{state:?}");
+            }
+        }
+    }
+}
+
+#[test]
+fn test_keystroke_trigger() {
+    let ctrl = Keysym(XKeysym::Control_L);
+    let f = Keysym(XKeysym::f);
+    let ctrl_mask = ModMask::empty() | ctrl;
+    let sent = |d: &KeyEventType| d.at_event().map(KeyEvent::release);
+    for (trigger, press, release) in [
+        (Trigger::OnBoth, Some(false), Some(true)),
+        (Trigger::OnPress, Some(false), None),
+        (Trigger::OnRelease, None, Some(true)),
+    ] {
+        let mut state = State {
+            has_client: true,
+            keystrokes: vec![Keystroke::new(f, ctrl_mask).with_trigger(trigger)].into(),
+            ..State::default()
+        };
+        let _ = state.press(ctrl);
+        let down = state.press(f);
+        assert_eq!(sent(&down), press, "{trigger:?}");
+        // the compositor never sees the suppressed edge either
+        assert!(down.should_swallow(), "{trigger:?}");
+        let _ = state.release(ctrl);
+        let up = state.release(f);
+        assert_eq!(sent(&up), release, "{trigger:?}");
+        assert!(up.should_swallow(), "{trigger:?}");
+        assert!(state.pressed.is_empty() && state.pressed_triggers.is_empty());
+    }
+    assert_eq!(Keystroke::from((f, ctrl_mask)).trigger, Trigger::OnBoth);
+    // configurations saved before triggers existed still load
+    let ks: Keystroke = serde_json::from_str(r#"{"modifiers":4,"keysym":102}"#).unwrap();
+    assert_eq!(ks.trigger, Trigger::OnBoth);
+}
+
+#[test]
+fn test_set_key_grabs_without_modifiers() {
+    let ctrl = Keysym(XKeysym::Control_L);
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let h = Keysym(XKeysym::h);
+    let f1 = Keysym(XKeysym::F1);
+    let x = Keysym(XKeysym::x);
+    let mut state = State::default();
+    state
+        .on_set_key_grabs(
+            &[],
+            &[
+                "Ctrl+h".parse().unwrap(),
+                Keystroke::new(f1, ModMask::empty()),
+            ],
+        )
+        .unwrap();
+
+    // no key starts a global grab
+    for key in [ctrl, caps, x] {
+        assert_eq!(state.press(key), KeyEventType::ProcessNormally, "{key}");
+        assert!(!state.grab_all);
+    }
+    for key in [x, caps] {
+        assert_eq!(state.release(key), KeyEventType::ProcessNormally, "{key}");
+    }
+    // only the keystrokes are sent to the AT
+    assert!(state.press(h).should_swallow());
+    assert!(state.release(h).should_swallow());
+    assert_eq!(state.release(ctrl), KeyEventType::ProcessNormally);
+    assert_eq!(state.press(h), KeyEventType::ProcessNormally);
+    assert_eq!(state.release(h), KeyEventType::ProcessNormally);
+    assert!(state.press(f1).should_swallow());
+    assert!(state.release(f1).should_swallow());
+    assert!(!state.grab_all);
+}
+
+#[test]
+fn test_describe_configuration() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let insert = Keysym(XKeysym::Insert);
+    let ctrl_shift_h: Keystroke = "Ctrl+Shift+h".parse().unwrap();
+    let keystrokes = vec![
+        ctrl_shift_h.clone(),
+        Keystroke::new(Keysym(XKeysym::F1), ModMask::empty()),
+        Keystroke::new(
+            Keysym(XKeysym::f),
+            ModMask::empty() | Keysym(XKeysym::Control_R),
+        ),
+        Keystroke::new(Keysym(XKeysym::a), ModMask::empty() | insert),
+    ];
+    let mut state = State::default();
+    state.apply_key_grabs(&[caps, insert], &keystrokes).unwrap();
+
+    assert_eq!(state.modifiers(), [caps, insert]);
+    assert_eq!(state.keystrokes(), keystrokes.as_slice());
+    assert_eq!(
+        state.describe(),
+        [
+            "modifier Caps_Lock",
+            "modifier Insert",
+            "keystroke Ctrl+Shift+h",
+            "keystroke F1",
+            "keystroke Ctrl+f",
+            "keystroke Insert+a",
+        ]
+    );
+    // standard modifiers round-trip through parsing
+    assert_eq!(ctrl_shift_h.to_string().parse(), Ok(ctrl_shift_h));
+    assert!(State::default().describe().is_empty());
+}
+
+#[test]
+fn test_modifier_variants_match_through_process() {
+    use crate::state_machine::KeystrokePattern;
+    let f = Keysym(XKeysym::f);
+    for (configured, pressed) in [
+        (XKeysym::Control_L, XKeysym::Control_R),
+        (XKeysym::Alt_L, XKeysym::Meta_R),
+        (XKeysym::Super_R, XKeysym::Hyper_L),
+        (XKeysym::Caps_Lock, XKeysym::Shift_Lock),
+    ] {
+        let configured = ModMask::empty() | Keysym(configured);
+        let pressed = Keysym(pressed);
+        let mut state = State {
+            has_client: true,
+            keystrokes: vec![Keystroke::new(f, configured)].into(),
+            ..State::default()
+        };
+        let _ = state.press(pressed);
+        assert!(state.press(f).should_swallow(), "{pressed}");
+        let _ = state.release(f);
+        let _ = state.release(pressed);
+
+        // patterns are matched the same way
+        let mut state = State {
+            has_client: true,
+            patterns: vec![(KeystrokePattern::Exact(f), configured)],
+            ..State::default()
+        };
+        let _ = state.press(pressed);
+        assert!(state.press(f).should_swallow(), "{pressed}");
+    }
+}
+
+#[test]
+fn test_right_modifier_matches_left_grab() {
+    let ctrl_l = Keysym(XKeysym::Control_L);
+    let ctrl_r = Keysym(XKeysym::Control_R);
+    let f = Keysym(XKeysym::f);
+    assert_eq!((ModMask::empty() | ctrl_l).normalize(), ModMask::CONTROL);
+    assert_eq!((ModMask::empty() | ctrl_r).normalize(), ModMask::CONTROL);
+    assert_eq!(ModMask::CONTROL.normalize(), ModMask::CONTROL);
+    let insert = ModMask::empty() | Keysym(XKeysym::Insert);
+    assert_eq!(insert.normalize(), insert);
+
+    for mask in [ModMask::empty() | ctrl_l, ModMask::CONTROL] {
+        let mut state = State {
+            has_client: true,
+            keystrokes: vec![Keystroke::new(f, mask)].into(),
+            ..State::default()
+        };
+        assert_eq!(state.press(ctrl_r), KeyEventType::ProcessNormally);
+        let ev = state.press(f);
+        assert_eq!(
+            ev.at_event().and_then(KeyEvent::matched_keystroke),
+            Some(0),
+            "{mask:?}"
+        );
+        assert!(state.release(f).should_swallow());
+        let _ = state.release(ctrl_r);
+        // the left variant still matches too
+        let _ = state.press(ctrl_l);
+        assert!(state.press(f).should_swallow(), "{mask:?}");
+        let _ = state.release(f);
+        let _ = state.release(ctrl_l);
+        assert_eq!(state.press(f), KeyEventType::ProcessNormally);
+    }
+}
+
+#[test]
+fn test_interface_call_lifecycle() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let a = Keysym(XKeysym::a);
+    let f = Keysym(XKeysym::f);
+    let mut state = State::default();
+    assert_eq!(state.press(a), KeyEventType::ProcessNormally);
+    let _ = state.release(a);
+
+    state.on_watch();
+    assert!(state.has_client);
+    assert!(matches!(
+        state.press(a),
+        KeyEventType::SendToATAndProcess(_)
+    ));
+    let _ = state.release(a);
+
+    // a rejected SetKeyGrabs changes nothing
+    assert!(
+        state
+            .on_set_key_grabs(&[caps], &[Keystroke::new(caps, ModMask::empty())])
+            .is_err()
+    );
+    assert!(state.modifiers.is_empty());
+    state
+        .on_set_key_grabs(&[caps], &[Keystroke::new(f, ModMask::empty())])
+        .unwrap();
+    assert!(state.press(f).should_swallow());
+    let _ = state.release(f);
+
+    state.on_grab();
+    assert!(state.explicit_grab);
+    assert!(matches!(state.press(a), KeyEventType::SendToAT(_)));
+    let _ = state.release(a);
+    assert!(state.on_ungrab().is_empty());
+    assert!(!state.explicit_grab);
+
+    state.on_unwatch();
+    assert!(!state.notify_all);
+    assert!(state.has_client);
+    assert_eq!(state.press(a), KeyEventType::ProcessNormally);
+    let _ = state.release(a);
+    assert!(state.press(caps).should_swallow());
+    assert!(state.grab_all);
+}
+
+#[test]
+fn test_const_constructors() {
+    const CONTROL_L: Keysym = Keysym::new(0xffe3);
+    static BINDINGS: [(Keysym, ModMask); 2] = [
+        (Keysym::new(0x66), ModMask::new(0xffe3)),
+        (Keysym::new(0x68), ModMask::empty()),
+    ];
+    const _: () = assert!(ModMask::empty().0.raw() == 0);
+    assert_eq!(CONTROL_L, Keysym(XKeysym::Control_L));
+    assert_eq!(BINDINGS[0].1, ModMask::empty() | CONTROL_L);
+    assert_eq!(BINDINGS[1].0, Keysym(XKeysym::h));
+    assert_eq!(ModMask::new(1 << 2), ModMask::CONTROL);
+}
+
+#[test]
+fn test_key_event_multi_codepoint_text() {
+    let e = Keysym(XKeysym::e);
+    let event = KeyEvent::new(false, ModMask::empty(), e, Some('e'), 18);
+    assert_eq!(event.unistr().as_deref(), Some("e"));
+
+    // e followed by a combining acute accent, as composed by the compositor
+    let composed = event.clone().with_text("e\u{301}");
+    assert_eq!(composed.unichar(), None);
+    assert_eq!(composed.unistr().as_deref(), Some("e\u{301}"));
+    assert_eq!(composed.to_signal_args().3, '\0');
+
+    let single = event.clone().with_text("é");
+    assert_eq!(single.unichar(), Some('é'));
+    assert_eq!(single.unistr().as_deref(), Some("é"));
+    let none = event.with_text("");
+    assert_eq!((none.unichar(), none.unistr()), (None, None));
+}
+
+#[test]
+fn test_flush_pressed() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let a = Keysym(XKeysym::a);
+    let b = Keysym(XKeysym::b);
+    let mut state = State {
+        has_client: true,
+        modifiers: vec![caps],
+        ..State::default()
+    };
+    let held = ModMask::empty();
+    let _ = state.press(caps);
+    let _ = state.press(a);
+    let _ = state.press(b);
+    assert_eq!(
+        state.on_ungrab(),
+        vec![
+            KeyEventType::SendToAT(KeyEvent::new(true, held, a, None, 0)),
+            KeyEventType::SendToAT(KeyEvent::new(true, held, b, None, 0)),
+        ]
+    );
+    assert!(state.pressed.is_empty());
+    assert!(!state.grab_all);
+    // repeats and the physical releases reach neither the AT nor the compositor
+    assert_eq!(state.press(a), KeyEventType::Swallow);
+    assert_eq!(state.release(a), KeyEventType::Swallow);
+    assert_eq!(state.release(b), KeyEventType::Swallow);
+    assert_eq!(state.release(caps), KeyEventType::Swallow);
+    assert!(state.flushed.is_empty());
+    assert_eq!(state.press(a), KeyEventType::ProcessNormally);
+    assert_eq!(state.release(a), KeyEventType::ProcessNormally);
+    assert!(state.flush_pressed().is_empty());
+}
+
+#[test]
+fn test_keystroke_required_locks() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let h = Keysym(XKeysym::h);
+    let mut state = State {
+        has_client: true,
+        keystrokes: vec![
+            Keystroke::new(h, ModMask::empty()).with_required_locks(ModMask::CAPS_LOCK),
+        ]
+        .into(),
+        ..State::default()
+    };
+    // Caps Lock not engaged
+    assert_eq!(state.press(h), KeyEventType::ProcessNormally);
+    assert_eq!(state.release(h), KeyEventType::ProcessNormally);
+    // engage it; the compositor sees the toggle
+    assert_eq!(state.press(caps), KeyEventType::ProcessNormally);
+    assert_eq!(state.release(caps), KeyEventType::ProcessNormally);
+    assert_eq!(state.locked, ModMask::CAPS_LOCK);
+    let ev = state.press(h);
+    assert_eq!(ev.at_event().and_then(KeyEvent::matched_keystroke), Some(0));
+    assert!(state.release(h).should_swallow());
+    // other locks may be engaged as well
+    state.locked = ModMask::CAPS_LOCK | ModMask::NUM_LOCK;
+    assert!(state.press(h).should_swallow());
+    let _ = state.release(h);
+    state.locked = ModMask::NUM_LOCK;
+    assert_eq!(state.press(h), KeyEventType::ProcessNormally);
+    assert_eq!(state.keystrokes.position(h, ModMask::empty()), None);
+}
+
+#[test]
+fn test_parse_keystroke() {
+    use crate::ParseKeysymError;
+    use crate::state_machine::ParseKeystrokeError;
+    let h = Keysym(XKeysym::h);
+    assert_eq!(
+        "Ctrl+Shift+h".parse(),
+        Ok(Keystroke::new(h, ModMask::CONTROL | ModMask::SHIFT))
+    );
+    assert_eq!(
+        Keystroke::try_from("control + alt + Return"),
+        Ok(Keystroke::new(
+            Keysym(XKeysym::Return),
+            ModMask::CONTROL | ModMask::ALT
+        ))
+    );
+    assert_eq!(
+        Keystroke::try_from("Super+Meta+H"),
+        Ok(Keystroke::new(
+            Keysym(XKeysym::H),
+            ModMask::SUPER | ModMask::ALT
+        ))
+    );
+    assert_eq!("h".parse(), Ok(Keystroke::new(h, ModMask::empty())));
+    assert_eq!(
+        "Ctrl+Shift".parse::<Keystroke>(),
+        Err(ParseKeystrokeError::MissingKey)
+    );
+    assert_eq!(
+        "Ctrl+a+b".parse::<Keystroke>(),
+        Err(ParseKeystrokeError::MultipleKeys("b".into()))
+    );
+    assert_eq!(
+        "Hyperr+h".parse::<Keystroke>(),
+        Err(ParseKeystrokeError::Key(ParseKeysymError::UnknownName(
+            "Hyperr".into()
+        )))
+    );
+    assert_eq!(
+        "Ctrl+".parse::<Keystroke>(),
+        Err(ParseKeystrokeError::Key(ParseKeysymError::Empty))
+    );
+}
+
+#[test]
+fn test_mod_mask_display() {
+    assert_eq!(ModMask::empty().to_string(), "<none>");
+    assert_eq!(ModMask::SHIFT.to_string(), "Shift");
+    assert_eq!(
+        (ModMask::SHIFT | ModMask::CONTROL).to_string(),
+        "Ctrl+Shift"
+    );
+    assert_eq!(
+        ModMask::from_mods(&[ModMask::SUPER, ModMask::CAPS_LOCK, ModMask::ALT]).to_string(),
+        "Alt+Super+CapsLock"
+    );
+    assert_eq!(
+        (ModMask::empty() | Keysym(XKeysym::Control_R)).to_string(),
+        "Ctrl"
+    );
+    assert_eq!(
+        (ModMask::empty() | Keysym(XKeysym::Insert)).to_string(),
+        "Insert"
+    );
+    let parsed: Keystroke = format!("{}+h", ModMask::SHIFT | ModMask::CONTROL)
+        .parse()
+        .unwrap();
+    assert_eq!(parsed.modifiers, ModMask::SHIFT | ModMask::CONTROL);
+}
+
+#[test]
+fn test_explicit_grab_survives_modifier_release() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let a = Keysym(XKeysym::a);
+    let mut state = State {
+        has_client: true,
+        modifiers: vec![caps],
+        ..State::default()
+    };
+    state.on_grab();
+    assert!(state.explicit_grab && !state.grab_all);
+    assert!(state.press(a).should_swallow());
+    assert!(state.release(a).should_swallow());
+    // the global modifier neither starts nor ends the grab
+    let press = state.press(caps);
+    assert_eq!(press.at_event().and_then(KeyEvent::grab_transition), None);
+    assert!(state.grab_all);
+    let release = state.release(caps);
+    assert_eq!(release.at_event().and_then(KeyEvent::grab_transition), None);
+    assert!(!state.grab_all);
+    assert!(state.explicit_grab);
+    assert!(matches!(state.press(a), KeyEventType::SendToAT(_)));
+    let _ = state.release(a);
+    assert!(state.grab_status().explicit_grab);
+
+    assert!(state.on_ungrab().is_empty());
+    assert!(!state.explicit_grab);
+    assert_eq!(state.press(a), KeyEventType::ProcessNormally);
+}
+
+#[test]
+fn test_key_event_matches_logical() {
+    use core::time::Duration;
+    let h = Keysym(XKeysym::h);
+    let event = KeyEvent::new(false, ModMask::empty(), h, Some('h'), 0);
+    let hardware = KeyEvent::new(false, ModMask::empty(), h, Some('h'), 35)
+        .with_timestamp(Duration::from_millis(12))
+        .with_matched_keystroke(0);
+    assert_ne!(event, hardware);
+    assert!(event.matches_logical(&hardware));
+    assert!(hardware.matches_logical(&event));
+    let release = KeyEvent::new(true, ModMask::empty(), h, None, 35);
+    assert!(!event.matches_logical(&release));
+    let shifted = KeyEvent::new(false, ModMask::SHIFT, h, Some('h'), 0);
+    assert!(!event.matches_logical(&shifted));
+}
+
+#[test]
+fn test_decode_unichar() {
+    use crate::state_machine::InvalidUnichar;
+    assert_eq!(KeyEvent::decode_unichar(u32::from('h')), Ok(Some('h')));
+    assert_eq!(KeyEvent::decode_unichar(0x1f980), Ok(Some('🦀')));
+    assert_eq!(KeyEvent::decode_unichar(0), Ok(None));
+    assert_eq!(
+        KeyEvent::decode_unichar(0xd800),
+        Err(InvalidUnichar(0xd800))
+    );
+    assert_eq!(
+        KeyEvent::decode_unichar(0x11_0000),
+        Err(InvalidUnichar(0x11_0000))
+    );
+    let h = Keysym(XKeysym::h);
+    let (.., unichar, _) = KeyEvent::new(true, ModMask::empty(), h, None, 0).to_signal_args();
+    assert_eq!(KeyEvent::decode_unichar(unichar.into()), Ok(None));
+}
+
+#[test]
+fn test_passthrough_consumes_latch() {
+    let volume = Keysym(XKeysym::XF86_AudioRaiseVolume);
+    let a = Keysym(XKeysym::a);
+    let mut state = State {
+        has_client: true,
+        notify_all: true,
+        passthrough: vec![volume],
+        ..State::default()
+    };
+    state.latch(ModMask::SHIFT);
+    assert!(state.press(volume).should_process());
+    assert!(state.latched.is_empty());
+    let _ = state.release(volume);
+    let press = state.press(a);
+    let event = press.at_event().unwrap();
+    assert_eq!(event.state(), ModMask::empty());
+    assert_eq!(event.unichar(), Some('a'));
+}
+
+#[test]
+fn test_passthrough_during_grab() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let volume = Keysym(XKeysym::XF86_AudioRaiseVolume);
+    let a = Keysym(XKeysym::a);
+    let mut state = State {
+        has_client: true,
+        modifiers: vec![caps],
+        keystrokes: vec![Keystroke::new(volume, ModMask::empty())].into(),
+        passthrough: vec![volume, caps],
+        ..State::default()
+    };
+    // passthrough wins over keystrokes
+    assert_eq!(state.press(volume), KeyEventType::ProcessNormally);
+    assert_eq!(state.release(volume), KeyEventType::ProcessNormally);
+    // and over a global grab, but not over the global modifiers themselves
+    assert!(state.press(caps).should_swallow());
+    assert!(state.grab_all);
+    assert_eq!(state.press(volume), KeyEventType::ProcessNormally);
+    assert_eq!(state.release(volume), KeyEventType::ProcessNormally);
+    assert!(state.press(a).should_swallow());
+    let _ = state.release(a);
+    let _ = state.release(caps);
+
+    state.on_grab();
+    state.on_watch();
+    assert!(matches!(
+        state.press(volume),
+        KeyEventType::SendToATAndProcess(_)
+    ));
+    let _ = state.release(volume);
+    assert!(matches!(state.press(a), KeyEventType::SendToAT(_)));
+    // a key held as grabbed when it becomes exempt is still released to the AT
+    state.passthrough.push(a);
+    assert!(matches!(state.release(a), KeyEventType::SendToAT(_)));
+    assert!(matches!(
+        state.press(a),
+        KeyEventType::SendToATAndProcess(_)
+    ));
+}
+
+#[test]
+fn test_key_decision_json() {
+    use crate::state_machine::KeyDecision;
+    use core::time::Duration;
+    use serde_json::json;
+
+    let a = Keysym(XKeysym::a);
+    let json_of =
+        |decision: &KeyEventType| serde_json::to_value(KeyDecision::from(decision)).unwrap();
+    let mut state = State::default();
+
+    assert_eq!(
+        json_of(&KeyEventType::Swallow),
+        json!({
+            "decision": "Swallow", "to_at": false, "to_compositor": false,
+            "release": null, "keysym": null, "keysym_name": null, "state": null,
+            "unichar": null, "unistr": null, "keycode": null, "grab_transition": null,
+            "matched_keystroke": null, "timestamp": null,
+        })
+    );
+    let normal = state.press(a);
+    assert_eq!(json_of(&normal)["decision"], "ProcessNormally");
+    assert_eq!(json_of(&normal)["to_compositor"], true);
+    assert_eq!(json_of(&normal)["keysym"], json!(null));
+    let _ = state.release(a);
+
+    state.on_watch();
+    let watched = state.process_with_keycode(a, false, 38);
+    assert_eq!(
+        json_of(&watched),
+        json!({
+            "decision": "SendToATAndProcess", "to_at": true, "to_compositor": true,
+            "release": false, "keysym": 0x61, "keysym_name": "a", "state": 0,
+            "unichar": "a", "unistr": "a", "keycode": 38, "grab_transition": null,
+            "matched_keystroke": null, "timestamp": null,
+        })
+    );
+    let _ = state.release(a);
+
+    state.on_grab();
+    let _ = state.press(a);
+    let grabbed = state.process_at(a, true, Duration::from_millis(5));
+    let value = json_of(&grabbed);
+    assert_eq!(value["decision"], "SendToAT");
+    assert_eq!(
+        (value["to_at"].clone(), value["to_compositor"].clone()),
+        (json!(true), json!(false))
+    );
+    assert_eq!(value["release"], true);
+    assert_eq!(value["unichar"], json!(null));
+    assert_eq!(value["timestamp"], json!({ "secs": 0, "nanos": 5_000_000 }));
+}
+
+#[test]
+fn test_multi_client_state() {
+    use crate::state_machine::{ClientId, MultiClientState};
+
+    let ctrl = Keysym(XKeysym::Control_L);
+    let [h, j, a] = [Keysym(XKeysym::h), Keysym(XKeysym::j), Keysym(XKeysym::a)];
+    let orca = ClientId::from(":1.10");
+    let other = ClientId::from(":1.42");
+    let mut multi = MultiClientState::default();
+    assert!(multi.process(h, false).should_process());
+
+    multi
+        .client_mut(&orca)
+        .on_set_key_grabs(&[], &[Keystroke::new(h, ModMask::CONTROL)])
+        .unwrap();
+    multi
+        .client_mut(&other)
+        .on_set_key_grabs(&[], &[Keystroke::new(j, ModMask::CONTROL)])
+        .unwrap();
+    assert_eq!(multi.clients().collect::<Vec<_>>(), [&orca, &other]);
+
+    assert!(multi.process(ctrl, false).should_process());
+    for (key, client) in [(h, &orca), (j, &other)] {
+        assert!(multi.is_grabbing(key, false));
+        let press = multi.process(key, false);
+        assert!(!press.should_process());
+        let events: Vec<_> = press.at_events().collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].0, events[0].1.keysym()), (client, key));
+        let release = multi.process(key, true);
+        assert!(!release.should_process());
+        assert_eq!(
+            release.at_events().map(|(c, _)| c).collect::<Vec<_>>(),
+            [client]
+        );
+    }
+    let unrelated = multi.process(a, false);
+    assert!(unrelated.should_process());
+    assert_eq!(unrelated.at_events().count(), 0);
+    let _ = multi.process(a, true);
+
+    // a watching client sees keys grabbed by another, which are still hidden from the compositor
+    multi.client_mut(&other).on_watch();
+    let press = multi.process(h, false);
+    assert!(!press.should_process());
+    assert_eq!(
+        press.at_events().map(|(c, _)| c).collect::<Vec<_>>(),
+        [&orca, &other]
+    );
+    let _ = multi.process(h, true);
+
+    // removed while h is held: the compositor never saw its press, so it must not see the rest
+    assert!(!multi.process(h, false).should_process());
+    assert!(multi.remove_client(&orca).is_some());
+    assert!(multi.client(&orca).is_none());
+    assert!(multi.is_grabbing(h, true));
+    let repeat = multi.process(h, false);
+    assert!(repeat.orphaned && !repeat.should_process());
+    let release = multi.process(h, true);
+    assert!(!release.should_process());
+    // the watching client still sees the release of the press it was shown
+    assert!(
+        release
+            .at_events()
+            .any(|(c, ev)| c == &other && ev.release() && ev.keysym() == h)
+    );
+    assert!(!multi.is_grabbing(h, false));
+    assert!(multi.process(h, false).should_process());
+    let _ = multi.process(h, true);
+
+    // the same without any client left, for a held global modifier
+    let caps = Keysym(XKeysym::Caps_Lock);
+    multi
+        .client_mut(&other)
+        .on_set_key_grabs(&[caps], &[])
+        .unwrap();
+    assert!(!multi.process(caps, false).should_process());
+    let _ = multi.remove_client(&other);
+    assert_eq!(multi.clients().count(), 0);
+    let release = multi.process(caps, true);
+    assert!(release.decisions.is_empty() && !release.should_process());
+    assert!(multi.process(caps, false).should_process());
+}
+
+#[test]
+fn test_hex_formatting() {
+    let caps = Keysym(XKeysym::Caps_Lock);
+    assert_eq!(format!("{caps:#06x}"), "0xffe5");
+    assert_eq!(format!("{caps:X}"), "FFE5");
+    assert_eq!(format!("{:#010X}", Keysym(XKeysym::a)), "0x00000061");
+
+    let mask = ModMask::CONTROL | ModMask::SHIFT;
+    assert_eq!(format!("{mask:#06x}"), "0x0005");
+    assert_eq!(format!("{mask:X}"), "5");
+    // masks are formatted as they are, not normalized
+    let raw = ModMask(XKeysym::Control_R);
+    assert_eq!(format!("{raw:#x}"), "0xffe4");
+}
+
+#[test]
+fn test_multi_client_lock_state() {
+    use crate::state_machine::{ClientId, MultiClientState};
+
+    let caps = Keysym(XKeysym::Caps_Lock);
+    let grabbing = ClientId::from(":1.10");
+    let watching = ClientId::from(":1.42");
+    let mut multi = MultiClientState::default();
+    multi
+        .client_mut(&grabbing)
+        .on_set_key_grabs(&[caps], &[])
+        .unwrap();
+    multi.client_mut(&watching).on_watch();
+    let locked =
+        |multi: &MultiClientState| [&grabbing, &watching].map(|c| multi.client(c).unwrap().locked);
+
+    // hidden from the compositor by one client, so Caps Lock is not toggled for any of them
+    assert!(!multi.process(caps, false).should_process());
+    let _ = multi.process(caps, true);
+    assert_eq!(locked(&multi), [ModMask::empty(); 2]);
+
+    multi
+        .client_mut(&grabbing)
+        .on_set_key_grabs(&[], &[])
+        .unwrap();
+    assert!(multi.process(caps, false).should_process());
+    let _ = multi.process(caps, true);
+    assert_eq!(locked(&multi), [ModMask::CAPS_LOCK; 2]);
+}
+
+#[cfg(feature = "std")]
+mod dbus;
+
+/// Everything an AT or compositor commonly needs is reachable through the prelude alone.
+mod prelude_only {
+    use crate::prelude::*;
+
+    #[test]
+    fn test_prelude() {
+        let ctrl = ModMask::CONTROL;
+        let h: Keysym = "h".parse().unwrap();
+        let mut state = State::default();
+        state
+            .on_set_key_grabs(&[], &[Keystroke::new(h, ctrl)])
+            .unwrap();
+        let _ = state.press("Control_L".parse().unwrap());
+        let decision: KeyEventType = state.press(h);
+        let event: &KeyEvent = decision.at_event().unwrap();
+        assert_eq!(event.keysym(), h);
+
+        #[cfg(feature = "std")]
+        {
+            let proxy: Option<KeyboardMonitorProxy<'static>> = None;
+            assert!(proxy.is_none());
+        }
+    }
 }
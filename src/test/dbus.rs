@@ -0,0 +1,463 @@
+//! Tests of the D-Bus parts: the proxy, the server and the client helpers, over private
+//! peer-to-peer connections or a private `dbus-daemon`.
+
+#[cfg(feature = "testing")]
+use crate::state_machine::{KeyEvent, KeyEventType, Keystroke, Trigger};
+#[cfg(feature = "testing")]
+use crate::{Keysym, ModMask};
+#[cfg(feature = "testing")]
+use xkeysym::Keysym as XKeysym;
+
+/// Connect a [`KeyboardMonitorProxy`] to a [`KeyboardMonitorServer`] over an in-process
+/// peer-to-peer connection.
+#[cfg(feature = "testing")]
+async fn p2p_server(
+    server: crate::server::KeyboardMonitorServer,
+) -> (zbus::Connection, crate::KeyboardMonitorProxy<'static>) {
+    #[cfg(not(feature = "tokio"))]
+    use std::os::unix::net::UnixStream;
+    #[cfg(feature = "tokio")]
+    use tokio::net::UnixStream;
+    use zbus::connection::Builder;
+    let (server_stream, client_stream) = UnixStream::pair().unwrap();
+    #[cfg(not(feature = "tokio"))]
+    let (server_stream, client_stream) = (
+        async_io::Async::new(server_stream).unwrap(),
+        async_io::Async::new(client_stream).unwrap(),
+    );
+    let (server_conn, client_conn) = futures_lite::future::try_zip(
+        Builder::socket(server_stream)
+            .server(zbus::Guid::generate())
+            .unwrap()
+            .p2p()
+            .serve_at("/org/freedesktop/a11y/Manager", server)
+            .unwrap()
+            .build(),
+        Builder::socket(client_stream).p2p().build(),
+    )
+    .await
+    .unwrap();
+    let proxy = crate::KeyboardMonitorProxy::new(&client_conn)
+        .await
+        .unwrap();
+    (server_conn, proxy)
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn test_server_methods_drive_state() {
+    use crate::server::KeyboardMonitorServer;
+    zbus::block_on(async {
+        let server = KeyboardMonitorServer::default();
+        let state = server.state();
+        let (_conn, proxy) = p2p_server(server).await;
+
+        proxy.watch_keyboard().await.unwrap();
+        assert!(state.lock().unwrap().has_client);
+        assert!(state.lock().unwrap().notify_all);
+
+        let caps = Keysym(XKeysym::Caps_Lock);
+        let ctrl_f = (
+            Keysym(XKeysym::F),
+            ModMask::empty() | Keysym(XKeysym::Control_L),
+        );
+        proxy.set_key_grabs(&[caps], &[&ctrl_f]).await.unwrap();
+        assert_eq!(state.lock().unwrap().modifiers, vec![caps]);
+        assert_eq!(
+            state.lock().unwrap().keystrokes,
+            vec![Keystroke {
+                modifiers: ctrl_f.1,
+                keysym: ctrl_f.0,
+                trigger: Trigger::OnBoth,
+                required_locks: ModMask::empty(),
+            }]
+        );
+
+        let conflicting = (caps, ModMask::empty());
+        assert!(matches!(
+            proxy.set_key_grabs(&[caps], &[&conflicting]).await,
+            Err(zbus::Error::MethodError(name, _, _))
+                if name.as_str() == "org.freedesktop.DBus.Error.InvalidArgs"
+        ));
+        assert_eq!(state.lock().unwrap().keystrokes.len(), 1);
+
+        proxy.grab_keyboard().await.unwrap();
+        assert!(state.lock().unwrap().explicit_grab);
+        proxy.ungrab_keyboard().await.unwrap();
+        assert!(!state.lock().unwrap().explicit_grab);
+        proxy.unwatch_keyboard().await.unwrap();
+        assert!(!state.lock().unwrap().notify_all);
+    });
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn test_grab_guard_ungrabs_on_drop() {
+    use crate::server::KeyboardMonitorServer;
+    use core::time::Duration;
+    zbus::block_on(async {
+        let server = KeyboardMonitorServer::default();
+        let state = server.state();
+        let (_conn, proxy) = p2p_server(server).await;
+
+        let guard = proxy.grab_guard().await.unwrap();
+        assert!(state.lock().unwrap().explicit_grab);
+        drop(guard);
+        // the ungrab is sent in the background; yield to let it run on a single-threaded runtime
+        for _ in 0..5000 {
+            futures_lite::future::yield_now().await;
+            if !state.lock().unwrap().explicit_grab {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(!state.lock().unwrap().explicit_grab);
+
+        let guard = proxy.grab_guard().await.unwrap();
+        assert!(state.lock().unwrap().explicit_grab);
+        guard.release().await.unwrap();
+        assert!(!state.lock().unwrap().explicit_grab);
+    });
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn test_server_emits_key_event() {
+    use crate::server::{KeyboardMonitorServer, KeyboardMonitorServerSignals};
+    use futures_lite::StreamExt;
+    zbus::block_on(async {
+        let (conn, proxy) = p2p_server(KeyboardMonitorServer::default()).await;
+        let mut events = proxy.receive_key_event().await.unwrap();
+        let iface = conn
+            .object_server()
+            .interface::<_, KeyboardMonitorServer>("/org/freedesktop/a11y/Manager")
+            .await
+            .unwrap();
+        iface
+            .key_event(false, ModMask::empty(), Keysym(XKeysym::H), 'H'.into(), 35)
+            .await
+            .unwrap();
+        let args = events.next().await.unwrap();
+        let args = args.args().unwrap();
+        assert!(!args.released);
+        assert_eq!(args.keysym, Keysym(XKeysym::H));
+        assert_eq!(args.unichar, u32::from('H'));
+        assert_eq!(args.keycode, 35);
+    });
+}
+
+/// Drive the client helpers through whichever runtime zbus is built with.
+#[cfg(feature = "testing")]
+fn client_helpers_roundtrip() {
+    use crate::server::{KeyboardMonitorServer, KeyboardMonitorServerSignals};
+    use futures_lite::StreamExt;
+    zbus::block_on(async {
+        let server = KeyboardMonitorServer::default();
+        let state = server.state();
+        let (conn, proxy) = p2p_server(server).await;
+        proxy.try_watch_keyboard().await.unwrap();
+        assert!(state.lock().unwrap().notify_all);
+        let mut events = crate::client::key_events(&proxy).await.unwrap();
+        let iface = conn
+            .object_server()
+            .interface::<_, KeyboardMonitorServer>("/org/freedesktop/a11y/Manager")
+            .await
+            .unwrap();
+        iface
+            .key_event(true, ModMask::empty(), Keysym(XKeysym::h), 0, 35)
+            .await
+            .unwrap();
+        assert_eq!(
+            events.next().await,
+            Some(KeyEvent::new(
+                true,
+                ModMask::empty(),
+                Keysym(XKeysym::h),
+                None,
+                35
+            ))
+        );
+        let grab = proxy.grab_guard().await.unwrap();
+        assert!(state.lock().unwrap().explicit_grab);
+        grab.release().await.unwrap();
+        assert!(!state.lock().unwrap().explicit_grab);
+    });
+}
+
+#[cfg(all(feature = "testing", feature = "async-io", not(feature = "tokio")))]
+#[test]
+fn test_client_helpers_async_io() {
+    client_helpers_roundtrip();
+}
+
+/// zbus prefers tokio when both backends are enabled.
+#[cfg(all(feature = "testing", feature = "tokio"))]
+#[test]
+fn test_client_helpers_tokio() {
+    client_helpers_roundtrip();
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn test_client_key_event_stream() {
+    use crate::server::{KeyboardMonitorServer, KeyboardMonitorServerSignals};
+    use futures_lite::StreamExt;
+    zbus::block_on(async {
+        let (conn, proxy) = p2p_server(KeyboardMonitorServer::default()).await;
+        let mut events = core::pin::pin!(crate::client::key_events(&proxy).await.unwrap());
+        let iface = conn
+            .object_server()
+            .interface::<_, KeyboardMonitorServer>("/org/freedesktop/a11y/Manager")
+            .await
+            .unwrap();
+        let caps = Keysym(XKeysym::Caps_Lock);
+        iface
+            .key_event(false, ModMask::empty(), caps, 0, 58)
+            .await
+            .unwrap();
+        iface
+            .key_event(
+                false,
+                ModMask::empty() | caps,
+                XKeysym::H.into(),
+                'H'.into(),
+                35,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            events.next().await,
+            Some(KeyEvent::new(false, ModMask::empty(), caps, None, 58))
+        );
+        assert_eq!(
+            events.next().await,
+            Some(KeyEvent::new(
+                false,
+                ModMask::empty() | caps,
+                XKeysym::H.into(),
+                Some('H'),
+                35
+            ))
+        );
+    });
+}
+
+#[test]
+fn test_error_name_mapping() {
+    use crate::error::KeyboardMonitorError;
+    use zbus::Message;
+    let call = Message::method_call("/org/freedesktop/a11y/Manager", "GrabKeyboard")
+        .unwrap()
+        .build(&())
+        .unwrap();
+    let reply = |name: &str| {
+        let msg = Message::error(&call.header(), name)
+            .unwrap()
+            .build(&("not allowed",))
+            .unwrap();
+        KeyboardMonitorError::from(zbus::Error::from(msg))
+    };
+    assert!(matches!(
+        reply("org.freedesktop.DBus.Error.AccessDenied"),
+        KeyboardMonitorError::AccessDenied(Some(d)) if d == "not allowed"
+    ));
+    assert!(matches!(
+        reply("org.freedesktop.DBus.Error.ServiceUnknown"),
+        KeyboardMonitorError::Unsupported(_)
+    ));
+    assert!(matches!(
+        reply("org.freedesktop.DBus.Error.Failed"),
+        KeyboardMonitorError::Transport(zbus::Error::MethodError(..))
+    ));
+    assert!(matches!(
+        KeyboardMonitorError::from(zbus::Error::from(zbus::fdo::Error::AccessDenied(
+            "nope".to_string()
+        ))),
+        KeyboardMonitorError::AccessDenied(_)
+    ));
+    assert!(matches!(
+        KeyboardMonitorError::from(zbus::Error::InvalidReply),
+        KeyboardMonitorError::Transport(zbus::Error::InvalidReply)
+    ));
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn test_set_key_grabs_owned() {
+    use crate::server::KeyboardMonitorServer;
+    zbus::block_on(async {
+        let server = KeyboardMonitorServer::default();
+        let state = server.state();
+        let (_conn, proxy) = p2p_server(server).await;
+
+        let ctrl = ModMask::empty() | Keysym(XKeysym::Control_L);
+        let keystrokes = vec![(Keysym(XKeysym::F), ctrl), (Keysym(XKeysym::G), ctrl)];
+        proxy
+            .set_key_grabs_owned(&[Keysym(XKeysym::Caps_Lock)], &keystrokes)
+            .await
+            .unwrap();
+        assert_eq!(
+            state.lock().unwrap().keystrokes,
+            vec![
+                Keystroke::new(Keysym(XKeysym::F), ctrl),
+                Keystroke::new(Keysym(XKeysym::G), ctrl),
+            ]
+        );
+    });
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn test_mock_service() {
+    use crate::client::key_events;
+    use crate::testing::{Call, MockService};
+    use futures_lite::StreamExt;
+    zbus::block_on(async {
+        let mock = MockService::start().await.unwrap();
+        let proxy = mock.proxy();
+        let mut events = key_events(proxy).await.unwrap();
+
+        let caps = Keysym(XKeysym::Caps_Lock);
+        let ctrl_f = (
+            Keysym(XKeysym::F),
+            ModMask::empty() | Keysym(XKeysym::Control_L),
+        );
+        proxy.grab_keyboard().await.unwrap();
+        proxy.set_key_grabs(&[caps], &[&ctrl_f]).await.unwrap();
+        proxy.ungrab_keyboard().await.unwrap();
+        assert_eq!(
+            mock.calls(),
+            vec![
+                Call::GrabKeyboard,
+                Call::SetKeyGrabs(vec![caps], vec![ctrl_f]),
+                Call::UngrabKeyboard,
+            ]
+        );
+        mock.clear_calls();
+        assert!(mock.calls().is_empty());
+
+        let event = KeyEvent::new(true, ModMask::empty() | caps, Keysym(XKeysym::h), None, 35);
+        mock.emit_key_event(&event).await.unwrap();
+        assert_eq!(events.next().await, Some(event));
+    });
+}
+
+/// A private `dbus-daemon`, killed on drop.
+struct TestBus(std::process::Child, String);
+
+impl TestBus {
+    fn spawn() -> Self {
+        use std::io::{BufRead, BufReader};
+        use std::process::{Command, Stdio};
+        let mut child = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("dbus-daemon is required for this test");
+        let mut address = String::new();
+        BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        TestBus(child, address.trim().to_string())
+    }
+}
+
+impl Drop for TestBus {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[test]
+fn test_connect_uses_default_name_and_path() {
+    use crate::KeyboardMonitorProxy;
+    use crate::server::KeyboardMonitorServer;
+    let bus = TestBus::spawn();
+    zbus::block_on(async {
+        let server = KeyboardMonitorServer::default();
+        let state = server.state();
+        let _compositor = zbus::connection::Builder::address(bus.1.as_str())
+            .unwrap()
+            .name("org.freedesktop.a11y.Manager")
+            .unwrap()
+            .serve_at("/org/freedesktop/a11y/Manager", server)
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let proxy = KeyboardMonitorProxy::connect_address(&bus.1).await.unwrap();
+        proxy.watch_keyboard().await.unwrap();
+        assert!(state.lock().unwrap().notify_all);
+    });
+}
+
+#[test]
+fn test_proxy_for_custom_service() {
+    use crate::KeyboardMonitorProxy;
+    use crate::server::KeyboardMonitorServer;
+    let bus = TestBus::spawn();
+    zbus::block_on(async {
+        let server = KeyboardMonitorServer::default();
+        let state = server.state();
+        let _mock = zbus::connection::Builder::address(bus.1.as_str())
+            .unwrap()
+            .name("org.example.KeyboardMonitorMock")
+            .unwrap()
+            .serve_at("/org/example/Mock", server)
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let conn = zbus::connection::Builder::address(bus.1.as_str())
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let proxy = KeyboardMonitorProxy::for_service(
+            &conn,
+            "org.example.KeyboardMonitorMock",
+            "/org/example/Mock",
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            proxy.inner().interface(),
+            "org.freedesktop.a11y.KeyboardMonitor"
+        );
+        proxy.grab_keyboard().await.unwrap();
+        assert!(state.lock().unwrap().explicit_grab);
+        // the default name is not served on this bus
+        let default = KeyboardMonitorProxy::new(&conn).await.unwrap();
+        assert!(default.grab_keyboard().await.is_err());
+        assert!(
+            KeyboardMonitorProxy::for_service(&conn, "not a name", "/")
+                .await
+                .is_err()
+        );
+    });
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn test_server_ungrab_emits_flushed_releases() {
+    use crate::server::KeyboardMonitorServer;
+    use futures_lite::StreamExt;
+    zbus::block_on(async {
+        let server = KeyboardMonitorServer::default();
+        let state = server.state();
+        let (_conn, proxy) = p2p_server(server).await;
+        let mut events = proxy.receive_key_event().await.unwrap();
+        proxy.grab_keyboard().await.unwrap();
+        let h = Keysym(XKeysym::h);
+        assert!(state.lock().unwrap().press(h).should_swallow());
+        proxy.ungrab_keyboard().await.unwrap();
+        let signal = events.next().await.unwrap();
+        let args = signal.args().unwrap();
+        assert!(args.released);
+        assert_eq!(args.keysym, h);
+        assert_eq!(args.unichar, 0);
+        assert_eq!(state.lock().unwrap().release(h), KeyEventType::Swallow);
+    });
+}
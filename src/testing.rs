@@ -0,0 +1,169 @@
+//! An in-memory `org.freedesktop.a11y.KeyboardMonitor` service, to test ATs without a
+//! compositor.
+//!
+//! [`MockService`] serves the interface over a private peer-to-peer connection, records every
+//! method call made through its [`proxy`](MockService::proxy), and emits `KeyEvent` signals on
+//! demand.
+//!
+//! ```
+//! use freedesktop_a11y_keyboardmonitor::client::key_events;
+//! use freedesktop_a11y_keyboardmonitor::state_machine::KeyEvent;
+//! use freedesktop_a11y_keyboardmonitor::testing::{Call, MockService};
+//! use freedesktop_a11y_keyboardmonitor::{Keysym, ModMask};
+//! use futures_lite::StreamExt;
+//! use xkeysym::Keysym as XKeysym;
+//!
+//! zbus::block_on(async {
+//!     let mock = MockService::start().await?;
+//!     let proxy = mock.proxy();
+//!     let mut events = key_events(proxy).await?;
+//!
+//!     proxy.grab_keyboard().await?;
+//!     assert_eq!(mock.calls(), vec![Call::GrabKeyboard]);
+//!
+//!     let event = KeyEvent::new(false, ModMask::empty(), Keysym(XKeysym::a), Some('a'), 38);
+//!     mock.emit_key_event(&event).await?;
+//!     assert_eq!(events.next().await, Some(event));
+//!     zbus::Result::Ok(())
+//! })
+//! .unwrap();
+//! ```
+
+use crate::state_machine::KeyEvent;
+use crate::{KeyboardMonitorProxy, Keysym, ModMask};
+#[cfg(not(feature = "tokio"))]
+use async_io::Async;
+#[cfg(not(feature = "tokio"))]
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex, PoisonError};
+#[cfg(feature = "tokio")]
+use tokio::net::UnixStream;
+use zbus::connection::Builder;
+use zbus::object_server::SignalEmitter;
+use zbus::{Connection, Guid, interface};
+
+const PATH: &str = "/org/freedesktop/a11y/Manager";
+
+/// A method call received by a [`MockService`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Call {
+    /// `GrabKeyboard`
+    GrabKeyboard,
+    /// `SetKeyGrabs`, with its `modifiers` and `keystrokes`.
+    SetKeyGrabs(Vec<Keysym>, Vec<(Keysym, ModMask)>),
+    /// `UngrabKeyboard`
+    UngrabKeyboard,
+    /// `UnwatchKeyboard`
+    UnwatchKeyboard,
+    /// `WatchKeyboard`
+    WatchKeyboard,
+}
+
+#[derive(Debug, Default)]
+struct Recorder {
+    calls: Arc<Mutex<Vec<Call>>>,
+}
+
+impl Recorder {
+    fn record(&self, call: Call) {
+        self.calls
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(call);
+    }
+}
+
+#[interface(name = "org.freedesktop.a11y.KeyboardMonitor")]
+impl Recorder {
+    fn grab_keyboard(&self) {
+        self.record(Call::GrabKeyboard);
+    }
+    fn set_key_grabs(&self, modifiers: Vec<Keysym>, keystrokes: Vec<(Keysym, ModMask)>) {
+        self.record(Call::SetKeyGrabs(modifiers, keystrokes));
+    }
+    fn ungrab_keyboard(&self) {
+        self.record(Call::UngrabKeyboard);
+    }
+    fn unwatch_keyboard(&self) {
+        self.record(Call::UnwatchKeyboard);
+    }
+    fn watch_keyboard(&self) {
+        self.record(Call::WatchKeyboard);
+    }
+    #[zbus(signal)]
+    async fn key_event(
+        emitter: &SignalEmitter<'_>,
+        released: bool,
+        state: ModMask,
+        keysym: Keysym,
+        unichar: u32,
+        keycode: u16,
+    ) -> zbus::Result<()>;
+}
+
+/// A mock compositor, connected to a single [`KeyboardMonitorProxy`].
+#[derive(Debug)]
+pub struct MockService {
+    server: Connection,
+    proxy: KeyboardMonitorProxy<'static>,
+    calls: Arc<Mutex<Vec<Call>>>,
+}
+
+impl MockService {
+    /// Serve the interface on a new private connection.
+    ///
+    /// # Errors
+    ///
+    /// If the socket pair or either end of the connection can not be set up.
+    pub async fn start() -> zbus::Result<Self> {
+        let recorder = Recorder::default();
+        let calls = Arc::clone(&recorder.calls);
+        let (server_stream, client_stream) = UnixStream::pair()?;
+        #[cfg(not(feature = "tokio"))]
+        let (server_stream, client_stream) =
+            (Async::new(server_stream)?, Async::new(client_stream)?);
+        let (server, client) = futures_lite::future::try_zip(
+            Builder::socket(server_stream)
+                .server(Guid::generate())?
+                .p2p()
+                .serve_at(PATH, recorder)?
+                .build(),
+            Builder::socket(client_stream).p2p().build(),
+        )
+        .await?;
+        let proxy = KeyboardMonitorProxy::new(&client).await?;
+        Ok(MockService {
+            server,
+            proxy,
+            calls,
+        })
+    }
+    /// The proxy connected to this service.
+    pub fn proxy(&self) -> &KeyboardMonitorProxy<'static> {
+        &self.proxy
+    }
+    /// All method calls received so far, oldest first.
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+    /// Forget the calls received so far.
+    pub fn clear_calls(&self) {
+        self.calls
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+    /// Emit `event` as a `KeyEvent` signal.
+    ///
+    /// # Errors
+    ///
+    /// If the signal could not be sent.
+    pub async fn emit_key_event(&self, event: &KeyEvent) -> zbus::Result<()> {
+        let (released, state, keysym, unichar, keycode) = event.to_signal_args();
+        let emitter = SignalEmitter::new(&self.server, PATH)?;
+        Recorder::key_event(&emitter, released, state, keysym, unichar.into(), keycode).await
+    }
+}